@@ -0,0 +1,97 @@
+#ifndef PRIMG_H
+#define PRIMG_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define PRIMG_TRIANGLE 0
+
+#define PRIMG_ELLIPSE 1
+
+#define PRIMG_RECTANGLE 2
+
+#define PRIMG_ROTATED_RECTANGLE 3
+
+typedef struct PrimgModel PrimgModel;
+
+/**
+ * A shape and its color.  params holds, in working image coordinates:
+ *   triangle: x1, y1, x2, y2, x3, y3
+ *   ellipse: x, y, rx, ry
+ *   rectangle: x1, y1, x2, y2
+ *   rotated rectangle: x, y, sx, sy, angle (degrees)
+ * Unused params are 0.
+ */
+typedef struct PrimgShape {
+  int32_t kind;
+  float params[6];
+  uint8_t r;
+  uint8_t g;
+  uint8_t b;
+  uint8_t a;
+} PrimgShape;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a model for width x height pixels of RGBA data, 4 bytes per pixel with no padding between
+ * rows.  Output (SVG) is scaled so its larger side is out_size.  workers is the number of threads,
+ * or 0 for one per physical CPU.  prescreen is how many of each step's random candidates are scored
+ * at full resolution after screening them all at half resolution, or 0 to score them all in full.
+ */
+struct PrimgModel *primg_model_new(const uint8_t *rgba,
+                                   uint32_t width,
+                                   uint32_t height,
+                                   uint32_t out_size,
+                                   uint32_t workers,
+                                   uint32_t prescreen);
+
+void primg_model_free(struct PrimgModel *model);
+
+/**
+ * Adds one shape of the given kind (PRIMG_TRIANGLE etc.), trying n random candidates in each of m
+ * rounds.  alpha and n must be at least 1.  Returns the index of the new shape, or -1.
+ */
+int32_t primg_model_step(struct PrimgModel *model,
+                         int32_t kind,
+                         uint8_t alpha,
+                         uint32_t n,
+                         uint8_t m);
+
+/**
+ * working image size, which shape coordinates are relative to
+ */
+uint32_t primg_model_width(const struct PrimgModel *model);
+
+uint32_t primg_model_height(const struct PrimgModel *model);
+
+/**
+ * root mean square difference from the target, 0 to 1, or -1
+ */
+float primg_model_score(const struct PrimgModel *model);
+
+uintptr_t primg_model_shape_count(const struct PrimgModel *model);
+
+/**
+ * Copies shape index into out.  Returns 0, or -1 if there's no such shape.
+ */
+int32_t primg_model_shape(const struct PrimgModel *model, uintptr_t index, struct PrimgShape *out);
+
+/**
+ * The model as an SVG document, to be released with primg_string_free, or NULL.
+ */
+char *primg_model_svg(const struct PrimgModel *model);
+
+void primg_string_free(char *s);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* PRIMG_H */
@@ -1,10 +1,12 @@
 extern crate clap;
 extern crate primg;
 
-use clap::{Arg, App};
+use clap::{App, AppSettings, Arg, SubCommand};
+use std::path::Path;
 
 fn main() {
     let matches = App::new("primg")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("shape")
             .help("Shape type (triangle, ellipse, rectangle, rotated-rectangle)")
             .short("t")
@@ -23,6 +25,11 @@ fn main() {
             .long("output-size")
             .takes_value(true)
             .default_value("1024"))
+        .arg(Arg::with_name("antialias")
+            .help("Anti-aliasing samples per pixel side for raster output (1-16, 1 disables)")
+            .long("antialias")
+            .takes_value(true)
+            .default_value("4"))
         .arg(Arg::with_name("alpha")
             .help("Alpha (1-255)")
             .short("a")
@@ -35,16 +42,132 @@ fn main() {
             .long("quality")
             .takes_value(true)
             .default_value("2"))
+        .arg(Arg::with_name("palette")
+            .help("Palette of hex colors (e.g. ff0000,00ff00) or path to a palette file")
+            .short("p")
+            .long("palette")
+            .takes_value(true))
+        .arg(Arg::with_name("palette-size")
+            .help("Extract a palette of this many colors from the image")
+            .short("k")
+            .long("palette-size")
+            .takes_value(true)
+            .conflicts_with("palette"))
+        .arg(Arg::with_name("palette-image")
+            .help("Image to extract the palette from instead of INFILE")
+            .long("palette-image")
+            .takes_value(true)
+            .requires("palette-size"))
+        .arg(Arg::with_name("min-size")
+            .help("Minimum shape size, as a fraction of the image size")
+            .long("min-size")
+            .takes_value(true))
+        .arg(Arg::with_name("max-size")
+            .help("Maximum shape size, as a fraction of the image size")
+            .long("max-size")
+            .takes_value(true))
+        .arg(Arg::with_name("min-area")
+            .help("Minimum shape area, as a fraction of the image area")
+            .long("min-area")
+            .takes_value(true))
+        .arg(Arg::with_name("max-aspect")
+            .help("Maximum ratio of a shape's longest to shortest dimension")
+            .long("max-aspect")
+            .takes_value(true))
+        .arg(Arg::with_name("schedule")
+            .help("Shrink the maximum shape size over the run (linear, exponential)")
+            .long("schedule")
+            .takes_value(true))
+        .arg(Arg::with_name("schedule-start")
+            .help("Maximum shape size at the first shape, as a fraction of the image size")
+            .long("schedule-start")
+            .takes_value(true)
+            .default_value("0.5"))
+        .arg(Arg::with_name("schedule-end")
+            .help("Maximum shape size at the last shape, as a fraction of the image size")
+            .long("schedule-end")
+            .takes_value(true)
+            .default_value("0.05"))
+        .arg(Arg::with_name("optimizer")
+            .help("Search strategy (hill-climb, anneal, population)")
+            .short("o")
+            .long("optimizer")
+            .takes_value(true)
+            .default_value("hill-climb"))
+        .arg(Arg::with_name("prescreen")
+            .help("Number of each 1000 random candidates promoted from screening at half resolution (0 to disable)")
+            .long("prescreen")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("fast-rectangles")
+            .help("Score rectangles from row sums: much faster, but only approximately the exact score")
+            .long("fast-rectangles"))
+        .arg(Arg::with_name("refine")
+            .help("Number of passes re-optimizing placed shapes after adding them")
+            .short("r")
+            .long("refine")
+            .takes_value(true)
+            .default_value("0"))
+        .arg(Arg::with_name("prune-threshold")
+            .help("Remove shapes that improve the score by less than this")
+            .long("prune-threshold")
+            .takes_value(true))
+        .arg(Arg::with_name("prune-to")
+            .help("Remove the least useful shapes until at most this many are left")
+            .long("prune-to")
+            .takes_value(true))
+        .arg(Arg::with_name("grayscale")
+            .help("Render in shades of gray")
+            .long("grayscale")
+            .conflicts_with_all(&["palette", "palette-size", "duotone"]))
+        .arg(Arg::with_name("duotone")
+            .help("Render luminance as a ramp between two hex colors (e.g. 000033,ffeecc)")
+            .long("duotone")
+            .takes_value(true)
+            .conflicts_with_all(&["palette", "palette-size"]))
         .arg(Arg::with_name("INFILE")
             .help("Path to image file")
             .required(true))
+        .arg(Arg::with_name("optimize-svg")
+            .help("Write compact SVG: a viewBox instead of a fixed size, rounded coordinates and shared attributes")
+            .long("optimize-svg"))
+        .arg(Arg::with_name("svg-precision")
+            .help("Decimal places kept in optimized SVG coordinates [default: 2]")
+            .long("svg-precision")
+            .takes_value(true)
+            .requires("optimize-svg"))
+        .arg(Arg::with_name("minify-svg")
+            .help("Leave out line breaks and leading zeros in optimized SVG")
+            .long("minify-svg")
+            .requires("optimize-svg"))
+        .arg(Arg::with_name("json")
+            .help("Print each shape to stdout as a line of JSON as it's found")
+            .long("json"))
         .arg(Arg::with_name("OUTFILE")
-            .help("Output file path")
-            .required(true))
+            .help("Output file path: an image, .svg, .pdf, .eps, .html for a page playing back the shapes, or a shape stream (.shapes for text, .shapes.bin for binary)")
+            .required_unless("json"))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Runs an HTTP server that takes images and streams back shapes as they're found")
+            .arg(Arg::with_name("host")
+                .help("Address to listen on")
+                .long("host")
+                .takes_value(true)
+                .default_value("127.0.0.1"))
+            .arg(Arg::with_name("port")
+                .help("Port to listen on")
+                .long("port")
+                .takes_value(true)
+                .default_value("8080")))
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("serve") {
+        let addr = format!("{}:{}", matches.value_of("host").unwrap(), matches.value_of("port").unwrap());
+        primg::serve(&addr).expect("couldn't start server");
+        return;
+    }
+
     let in_path = String::from(matches.value_of("INFILE").unwrap());
-    let out_path = String::from(matches.value_of("OUTFILE").unwrap());
+    let out_path = matches.value_of("OUTFILE").map(String::from);
     let num_shapes = matches.value_of("num-shapes").unwrap().parse::<u32>().unwrap();
     let shape_type = match matches.value_of("shape").unwrap().to_lowercase().as_ref() {
         "triangle" => primg::ShapeType::Triangle,
@@ -53,7 +176,45 @@ fn main() {
         "rotated-rectangle" => primg::ShapeType::RotatedRectangle,
         _ => panic!("invalid shape"),
     };
+    let mut constraints = primg::Constraints::default();
+    if let Some(v) = matches.value_of("min-size") {
+        constraints.min_size = v.parse::<f32>().unwrap();
+    }
+    if let Some(v) = matches.value_of("max-size") {
+        constraints.max_size = v.parse::<f32>().unwrap();
+    }
+    if let Some(v) = matches.value_of("min-area") {
+        constraints.min_area = v.parse::<f32>().unwrap();
+    }
+    if let Some(v) = matches.value_of("max-aspect") {
+        constraints.max_aspect = v.parse::<f32>().unwrap();
+    }
+    assert!(constraints.min_size <= constraints.max_size, "min-size must not exceed max-size");
+    assert!(constraints.max_aspect >= 1.0, "max-aspect must be at least 1");
+    let schedule = matches.value_of("schedule").map(|decay| {
+        let decay = match decay.to_lowercase().as_ref() {
+            "linear" => primg::Decay::Linear,
+            "exponential" => primg::Decay::Exponential,
+            _ => panic!("invalid schedule"),
+        };
+        let start = matches.value_of("schedule-start").unwrap().parse::<f32>().unwrap();
+        let end = matches.value_of("schedule-end").unwrap().parse::<f32>().unwrap();
+        assert!(start > 0.0 && end > 0.0, "schedule sizes must be positive");
+        primg::Schedule { decay, start, end }
+    });
+    let optimizer = match matches.value_of("optimizer").unwrap().to_lowercase().as_ref() {
+        "hill-climb" => primg::OptimizerType::HillClimb,
+        "anneal" => primg::OptimizerType::Annealing,
+        "population" => primg::OptimizerType::Population,
+        _ => panic!("invalid optimizer"),
+    };
+    let prescreen = matches.value_of("prescreen").unwrap().parse::<usize>().unwrap();
+    let refine = matches.value_of("refine").unwrap().parse::<u32>().unwrap();
+    let prune_threshold = matches.value_of("prune-threshold").map(|t| t.parse::<f32>().unwrap());
+    let prune_to = matches.value_of("prune-to").map(|n| n.parse::<usize>().unwrap());
     let out_size = matches.value_of("output-size").unwrap().parse::<usize>().unwrap();
+    let antialias = matches.value_of("antialias").unwrap().parse::<usize>().unwrap();
+    assert!((1..=16).contains(&antialias), "antialias must be between 1-16");
     let alpha = matches.value_of("alpha").unwrap().parse::<u8>().unwrap();
     let m = matches.value_of("quality").unwrap().parse::<u8>().unwrap();
     let m = match m {
@@ -62,6 +223,35 @@ fn main() {
         3 => 16,
         _ => panic!("quality must be between 1-3")
     };
+    let palette = matches.value_of("palette").map(|p| {
+        if Path::new(p).is_file() {
+            primg::Palette::load(p).expect("couldn't load palette")
+        } else {
+            primg::Palette::parse(p).expect("invalid palette")
+        }
+    });
+    let palette_size = matches.value_of("palette-size").map(|k| k.parse::<usize>().unwrap());
+    let palette_image = matches.value_of("palette-image").map(String::from);
+    let mode = if matches.is_present("grayscale") {
+        primg::ColorMode::Grayscale
+    } else if let Some(colors) = matches.value_of("duotone") {
+        let colors = primg::Palette::parse(colors).expect("invalid duotone colors");
+        assert!(colors.colors().len() == 2, "duotone takes exactly two colors");
+        primg::ColorMode::Duotone(colors.colors()[0], colors.colors()[1])
+    } else {
+        primg::ColorMode::Color
+    };
+
+    let svg = if matches.is_present("optimize-svg") {
+        let mut options = primg::SvgOptions::default();
+        if let Some(p) = matches.value_of("svg-precision") {
+            options.precision = p.parse::<usize>().unwrap();
+        }
+        options.minify = matches.is_present("minify-svg");
+        Some(options)
+    } else {
+        None
+    };
 
     assert!(alpha > 0, "alpha must be between 1-255");
 
@@ -73,6 +263,21 @@ fn main() {
         out_size,
         alpha,
         m,
+        palette,
+        palette_size,
+        palette_image,
+        mode,
+        optimizer,
+        prescreen,
+        fast_rectangles: matches.is_present("fast-rectangles"),
+        constraints,
+        schedule,
+        refine,
+        prune_threshold,
+        prune_to,
+        antialias,
+        svg,
+        json: matches.is_present("json"),
     };
     primg::run(config);
 }
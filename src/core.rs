@@ -1,10 +1,12 @@
 use super::SIZE;
 use image::{Pixel, Rgba, RgbaImage};
 use image::math::utils::clamp;
+use std::cmp;
 use std::fmt;
 use std::marker::Sync;
 
 use scanline::Scanline;
+use simd;
 use util;
 
 #[derive(Clone)]
@@ -52,6 +54,58 @@ impl Pixels {
         self.buf[i + 3] = color.a();
     }
 
+    pub fn desaturate(&mut self) {
+        for p in self.buf.chunks_mut(4) {
+            let l = Color::from(p).luma();
+            p[0] = l;
+            p[1] = l;
+            p[2] = l;
+        }
+    }
+
+    // half the size in each dimension, each pixel the average of a 2x2 block
+    pub fn half(&self) -> Pixels {
+        let mut half = Pixels::new(self.w / 2, self.h / 2);
+        for y in 0..half.h {
+            half.update_half_row(self, y, 0, half.w);
+        }
+        half
+    }
+
+    // Updates a half image after lines were drawn on the full size src, and returns the change in its
+    // squared error against target.  A row two lines share is updated twice, the second time without
+    // changing anything.
+    pub fn update_half(&mut self, src: &Pixels, target: &Pixels, lines: &[Scanline]) -> i64 {
+        let mut delta = 0;
+        for line in lines {
+            let y = line.y / 2;
+            let (x1, x2) = (line.x1 / 2, cmp::min(line.x2 / 2 + 1, self.w));
+            if y < self.h && x1 < x2 {
+                let (a, b) = (self.index(x1, y), self.index(x2, y));
+                let t = &target.buf[a..b];
+                // the squared error is the change from matching target exactly
+                delta -= simd::error_delta(t, t, &self.buf[a..b]);
+                self.update_half_row(src, y, x1, x2);
+                delta += simd::error_delta(t, t, &self.buf[a..b]);
+            }
+        }
+        delta
+    }
+
+    // pixels x1..x2 (exclusive) of row y
+    fn update_half_row(&mut self, src: &Pixels, y: usize, x1: usize, x2: usize) {
+        for x in x1..x2 {
+            let a = src.index(2 * x, 2 * y);
+            let b = src.index(2 * x, 2 * y + 1);
+            let i = self.index(x, y);
+            for k in 0..4 {
+                let sum = src.buf[a + k] as u32 + src.buf[a + 4 + k] as u32 +
+                    src.buf[b + k] as u32 + src.buf[b + 4 + k] as u32;
+                self.buf[i + k] = ((sum + 2) / 4) as u8;
+            }
+        }
+    }
+
     pub fn erase(&mut self, color: &Color) {
         util::erase(&mut self.buf, color);
     }
@@ -76,36 +130,16 @@ impl Pixels {
     }
 
     pub fn compute_color(&self, target: &Pixels, lines: &[Scanline], alpha: u8) -> Color {
-        let mut rsum = 0;
-        let mut gsum = 0;
-        let mut bsum = 0;
-        let mut count = 0;
-        let a = 0xffff / (alpha as i32);
-        unsafe {
-            for line in lines {
-                let mut i = target.index(line.x1, line.y);
-                for _ in line.x1..line.x2 + 1 {
-                    let tr = *target.buf.get_unchecked(i + 0) as i32;
-                    let tg = *target.buf.get_unchecked(i + 1) as i32;
-                    let tb = *target.buf.get_unchecked(i + 2) as i32;
-                    let cr = *self.buf.get_unchecked(i + 0) as i32;
-                    let cg = *self.buf.get_unchecked(i + 1) as i32;
-                    let cb = *self.buf.get_unchecked(i + 2) as i32;
-                    rsum += (tr - cr) * a + cr * 0x101;
-                    gsum += (tg - cg) * a + cg * 0x101;
-                    bsum += (tb - cb) * a + cb * 0x101;
-                    count += 1;
-                    i += 4;
-                }
-            }
-        }
-        if count == 0 {
-            return Color::new(0, 0, 0, 0);
+        let mut diffs = [0i64; 3];
+        let mut sums = [0i64; 3];
+        let mut count = 0i64;
+        for line in lines {
+            let n = (line.x2 + 1).saturating_sub(line.x1);
+            let i = target.index(line.x1, line.y);
+            simd::color_sums(&target.buf[i..i + 4 * n], &self.buf[i..i + 4 * n], &mut diffs, &mut sums);
+            count += n as i64;
         }
-        let r = clamp((rsum / count) >> 8, 0, 255);
-        let g = clamp((gsum / count) >> 8, 0, 255);
-        let b = clamp((bsum / count) >> 8, 0, 255);
-        return Color::new(r as u8, g as u8, b as u8, alpha);
+        Color::fit(&diffs, &sums, count, alpha)
     }
 
     pub fn copy_lines(&mut self, src: &Pixels, lines: &[Scanline]) {
@@ -124,7 +158,7 @@ impl Pixels {
     pub fn difference_full(a: &Pixels, b: &Pixels) -> f32 {
         let w = a.w;
         let h = a.h;
-        let mut total = 0i32;
+        let mut total = 0i64;
         for y in 0..h {
             for x in 0..w {
                 let pa = a.get_arr(x, y);
@@ -134,7 +168,7 @@ impl Pixels {
                 let dg = pa[1] as i32 - pb[1] as i32;
                 let db = pa[2] as i32 - pb[2] as i32;
                 let da = pa[3] as i32 - pb[3] as i32;
-                total += (dr * dr) + (dg * dg) + (db * db) + (da * da);
+                total += ((dr * dr) + (dg * dg) + (db * db) + (da * da)) as i64;
             }
         }
         (total as f32 / (w * h * 4) as f32).sqrt() / 255.0
@@ -146,28 +180,12 @@ impl Pixels {
                               score: f32,
                               lines: &[Scanline]) -> f32 {
         let ni = target.w * target.h * 4;
-        let mut total = ((score * 255.0).powi(2) * ni as f32) as i32;
-
-        unsafe {
-            for line in lines {
-                let mut i = target.index(line.x1, line.y);
-                for _ in line.x1..line.x2 + 1 {
-                    let dr1 = *target.buf.get_unchecked(i + 0) as i32 - *before.buf.get_unchecked(i + 0) as i32;
-                    let dg1 = *target.buf.get_unchecked(i + 1) as i32 - *before.buf.get_unchecked(i + 1) as i32;
-                    let db1 = *target.buf.get_unchecked(i + 2) as i32 - *before.buf.get_unchecked(i + 2) as i32;
-                    let da1 = *target.buf.get_unchecked(i + 3) as i32 - *before.buf.get_unchecked(i + 3) as i32;
-
-                    let dr2 = *target.buf.get_unchecked(i + 0) as i32 - *after.buf.get_unchecked(i + 0) as i32;
-                    let dg2 = *target.buf.get_unchecked(i + 1) as i32 - *after.buf.get_unchecked(i + 1) as i32;
-                    let db2 = *target.buf.get_unchecked(i + 2) as i32 - *after.buf.get_unchecked(i + 2) as i32;
-                    let da2 = *target.buf.get_unchecked(i + 3) as i32 - *after.buf.get_unchecked(i + 3) as i32;
-
-                    total -= (dr1 * dr1) + (dg1 * dg1) + (db1 * db1) + (da1 * da1);
-                    total += (dr2 * dr2) + (dg2 * dg2) + (db2 * db2) + (da2 * da2);
-
-                    i += 4;
-                }
-            }
+        let mut total = ((score * 255.0).powi(2) * ni as f32) as i64;
+
+        for line in lines {
+            let n = (line.x2 + 1).saturating_sub(line.x1);
+            let i = target.index(line.x1, line.y);
+            total += simd::error_delta(&target.buf[i..i + 4 * n], &before.buf[i..i + 4 * n], &after.buf[i..i + 4 * n]);
         }
         (total as f32 / ni as f32).sqrt() / 255.0
     }
@@ -177,7 +195,7 @@ impl Pixels {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Color(u32);
 
 impl Color {
@@ -189,6 +207,33 @@ impl Color {
         Color::new(c[0], c[1], c[2], c[3])
     }
 
+    // "rrggbb" with an optional leading '#'
+    pub fn parse_hex(s: &str) -> Option<Color> {
+        let s = s.trim_start_matches('#');
+        if s.len() != 6 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let v = u32::from_str_radix(s, 16).ok()?;
+        Some(Color::new((v >> 16) as u8, (v >> 8) as u8, v as u8, 255))
+    }
+
+    // The color that, blended with alpha over count pixels, best matches the target, from the per
+    // channel sums of target - current and of current over those pixels.
+    pub fn fit(diffs: &[i64; 3], sums: &[i64; 3], count: i64, alpha: u8) -> Color {
+        if count == 0 {
+            return Color::new(0, 0, 0, 0);
+        }
+        // sum over the pixels of (t - c) * a + c * 0x101
+        let a = 0xffff / (alpha as i64);
+        let channel = |k: usize| clamp(((diffs[k] * a + sums[k] * 0x101) / count) >> 8, 0, 255) as u8;
+        Color::new(channel(0), channel(1), channel(2), alpha)
+    }
+
+    // Rec. 601 luma
+    pub fn luma(&self) -> u8 {
+        ((299 * self.r() as u32 + 587 * self.g() as u32 + 114 * self.b() as u32 + 500) / 1000) as u8
+    }
+
     pub fn to_rgba(&self) -> Rgba<u8> {
         Pixel::from_channels(self.r(), self.g(), self.b(), self.a())
     }
@@ -216,6 +261,32 @@ impl Color {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColorMode {
+    Color,
+    Grayscale,
+    // luminance mapped onto a ramp from the first color (black) to the second (white)
+    Duotone(Color, Color),
+}
+
+impl ColorMode {
+    pub fn is_monochrome(&self) -> bool {
+        *self != ColorMode::Color
+    }
+
+    // maps a color found by the model to the color that should be output
+    pub fn apply(&self, c: &Color) -> Color {
+        match *self {
+            ColorMode::Color | ColorMode::Grayscale => *c,
+            ColorMode::Duotone(ref dark, ref light) => {
+                let t = c.r() as u32;
+                let lerp = |a: u8, b: u8| ((a as u32 * (255 - t) + b as u32 * t + 127) / 255) as u8;
+                Color::new(lerp(dark.r(), light.r()), lerp(dark.g(), light.g()), lerp(dark.b(), light.b()), c.a())
+            }
+        }
+    }
+}
+
 impl fmt::Display for Color {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -0,0 +1,131 @@
+use core::Color;
+use scanline::Scanline;
+use shape::Shape;
+use util;
+
+// Anti-aliased drawing for output.  A shape is rasterized on a grid `samples` times finer than the
+// output and each output pixel is blended in proportion to how many of its subpixels are covered.
+pub struct Coverage {
+    w: usize,
+    h: usize,
+    samples: usize,
+    counts: Vec<u16>,
+    lines: Vec<Scanline>,
+}
+
+impl Coverage {
+    pub fn new(w: usize, h: usize, samples: usize) -> Coverage {
+        assert!(samples > 0 && samples <= 16, "samples must be between 1-16");
+        let counts = vec![0; w * h];
+        let lines = Scanline::buffer(h * samples);
+        Coverage { w, h, samples, counts, lines }
+    }
+
+    // draws a shape in working coordinates, scaled to the output
+    pub fn draw(&mut self, buf: &mut [u8], shape: &Shape, scale: f32, color: &Color) {
+        let k = self.samples;
+        let s = scale * k as f32;
+        // shape coordinates are pixel centers, as in the svg
+        let shape = match *shape {
+            // whole pixels from x1 - 0.5 to x2 + 0.5, which cover the subpixels centered inside
+            Shape::Rectangle { x1, y1, x2, y2 } => Shape::Rectangle {
+                x1: (x1 * s - 0.5).ceil(),
+                y1: (y1 * s - 0.5).ceil(),
+                x2: ((x2 + 1.0) * s - 0.5).ceil() - 1.0,
+                y2: ((y2 + 1.0) * s - 0.5).ceil() - 1.0,
+            },
+            _ => {
+                let d = (s - 1.0) / 2.0;
+                shape.scaled(s).translated(d, d)
+            }
+        };
+        let lines = shape.rasterize(self.w * k, self.h * k, &mut self.lines);
+        if lines.is_empty() {
+            return;
+        }
+
+        let (mut x1, mut y1, mut x2, mut y2) = (self.w, self.h, 0, 0);
+        for line in lines {
+            let y = line.y / k;
+            let row = y * self.w;
+            for x in line.x1 / k..line.x2 / k + 1 {
+                let a = if line.x1 > x * k { line.x1 } else { x * k };
+                let b = if line.x2 < x * k + k - 1 { line.x2 } else { x * k + k - 1 };
+                self.counts[row + x] += (b - a + 1) as u16;
+            }
+            x1 = x1.min(line.x1 / k);
+            x2 = x2.max(line.x2 / k);
+            y1 = y1.min(y);
+            y2 = y2.max(y);
+        }
+
+        let full = (k * k) as u32;
+        let alpha = color.a() as u32;
+        for y in y1..y2 + 1 {
+            for x in x1..x2 + 1 {
+                let i = y * self.w + x;
+                let count = self.counts[i] as u32;
+                if count > 0 {
+                    util::blend(buf, 4 * i, color, (alpha * count + full / 2) / full);
+                    self.counts[i] = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // opacity of each pixel after drawing an opaque shape on a transparent image
+    fn draw(shape: &Shape, scale: f32, samples: usize, w: usize, h: usize) -> Vec<u8> {
+        let mut buf = vec![0; w * h * 4];
+        Coverage::new(w, h, samples).draw(&mut buf, shape, scale, &Color::new(255, 255, 255, 255));
+        buf.chunks(4).map(|p| p[3]).collect()
+    }
+
+    #[test]
+    fn rectangle_matches_svg() {
+        // the svg draws x from 5 to 15 and y from 7.5 to 12.5
+        let shape = Shape::Rectangle { x1: 2.0, y1: 3.0, x2: 5.0, y2: 4.0 };
+        let (w, h) = (20, 20);
+        let alphas = draw(&shape, 2.5, 4, w, h);
+        let area: f32 = alphas.iter().map(|&a| a as f32 / 255.0).sum();
+        assert!((area - 50.0).abs() < 0.5, "covered {}", area);
+        assert_eq!(alphas[10 * w + 4], 0);
+        assert_eq!(alphas[10 * w + 5], 255);
+        assert_eq!(alphas[10 * w + 14], 255);
+        assert_eq!(alphas[10 * w + 15], 0);
+        assert_eq!(alphas[7 * w + 10], 128);
+        assert_eq!(alphas[12 * w + 10], 128);
+        assert_eq!(alphas[13 * w + 10], 0);
+    }
+
+    #[test]
+    fn one_sample_matches_svg() {
+        // the svg draws x from 5 to 15 and y from 7.5 to 12.5, so pixels 5..14 and the 5 rows
+        // whose centers are inside, ties going to the top edge
+        let shape = Shape::Rectangle { x1: 2.0, y1: 3.0, x2: 5.0, y2: 4.0 };
+        let (w, h) = (20, 20);
+        let alphas = draw(&shape, 2.5, 1, w, h);
+        let covered: Vec<usize> = (0..w * h).filter(|&i| alphas[i] == 255).collect();
+        let expected: Vec<usize> = (7..12).flat_map(|y| (5..15).map(move |x| y * w + x)).collect();
+        assert_eq!(covered, expected);
+        assert!(alphas.iter().all(|&a| a == 0 || a == 255));
+
+        // an ellipse around pixel 2's center is around 6.25 in the output, where pixel centers are
+        // at 0.5, 1.5 and so on
+        let shape = Shape::Ellipse { x: 2.0, y: 2.0, rx: 1.0, ry: 1.0 };
+        let alphas = draw(&shape, 2.5, 1, w, h);
+        let xs: Vec<usize> = (0..w).filter(|&x| alphas[5 * w + x] == 255).collect();
+        assert_eq!(xs, [4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn rectangle_clipped_to_image() {
+        let shape = Shape::Rectangle { x1: 0.0, y1: 0.0, x2: 9.0, y2: 9.0 };
+        let alphas = draw(&shape, 1.0, 4, 10, 10);
+        assert!(alphas.iter().all(|&a| a == 255));
+    }
+}
@@ -0,0 +1,225 @@
+// C interface.  Models are opaque handles created by primg_model_new and released with
+// primg_model_free.  Functions that can fail return NULL or -1, and never unwind into the caller.
+// include/primg.h is generated from this file with cbindgen (see cbindgen.toml).
+
+use image::{DynamicImage, RgbaImage};
+use num_cpus;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic;
+use std::ptr;
+use std::slice;
+
+use core::ColorMode;
+use model::Model;
+use optimizer::OptimizerType;
+use shape::{Constraints, Shape, ShapeType};
+
+pub const PRIMG_TRIANGLE: i32 = 0;
+pub const PRIMG_ELLIPSE: i32 = 1;
+pub const PRIMG_RECTANGLE: i32 = 2;
+pub const PRIMG_ROTATED_RECTANGLE: i32 = 3;
+
+pub struct PrimgModel(Model);
+
+/// A shape and its color.  params holds, in working image coordinates:
+///   triangle: x1, y1, x2, y2, x3, y3
+///   ellipse: x, y, rx, ry
+///   rectangle: x1, y1, x2, y2
+///   rotated rectangle: x, y, sx, sy, angle (degrees)
+/// Unused params are 0.
+#[repr(C)]
+pub struct PrimgShape {
+    pub kind: i32,
+    pub params: [f32; 6],
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+/// Creates a model for width x height pixels of RGBA data, 4 bytes per pixel with no padding between
+/// rows.  Output (SVG) is scaled so its larger side is out_size.  workers is the number of threads,
+/// or 0 for one per physical CPU.  prescreen is how many of each step's random candidates are scored
+/// at full resolution after screening them all at half resolution, or 0 to score them all in full.
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_new(rgba: *const u8, width: u32, height: u32, out_size: u32,
+                                         workers: u32, prescreen: u32) -> *mut PrimgModel {
+    if rgba.is_null() || width == 0 || height == 0 {
+        return ptr::null_mut();
+    }
+    // the size can overflow usize on 32 bit targets
+    let n = match (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(4)) {
+        Some(n) => n,
+        None => return ptr::null_mut(),
+    };
+    let data = slice::from_raw_parts(rgba, n).to_vec();
+    let result = panic::catch_unwind(|| {
+        let img = RgbaImage::from_raw(width, height, data).expect("wtf");
+        let workers = if workers == 0 { num_cpus::get_physical() } else { workers as usize };
+        Model::new(DynamicImage::ImageRgba8(img), workers, out_size as usize, None, ColorMode::Color,
+                   OptimizerType::HillClimb.build(), prescreen as usize)
+    });
+    match result {
+        Ok(model) => Box::into_raw(Box::new(PrimgModel(model))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_free(model: *mut PrimgModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/// Adds one shape of the given kind (PRIMG_TRIANGLE etc.), trying n random candidates in each of m
+/// rounds.  alpha and n must be at least 1.  Returns the index of the new shape, or -1.
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_step(model: *mut PrimgModel, kind: i32, alpha: u8, n: u32, m: u8) -> i32 {
+    let t = match shape_type(kind) {
+        Some(t) => t,
+        None => return -1,
+    };
+    if alpha == 0 || n == 0 {
+        return -1;
+    }
+    let model = match model.as_mut() {
+        Some(&mut PrimgModel(ref mut model)) => model,
+        None => return -1,
+    };
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        model.step(t, alpha, n, m, Constraints::default());
+        model.num_shapes() as i32 - 1
+    }));
+    result.unwrap_or(-1)
+}
+
+/// working image size, which shape coordinates are relative to
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_width(model: *const PrimgModel) -> u32 {
+    model.as_ref().map_or(0, |m| m.0.w as u32)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_height(model: *const PrimgModel) -> u32 {
+    model.as_ref().map_or(0, |m| m.0.h as u32)
+}
+
+/// root mean square difference from the target, 0 to 1, or -1
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_score(model: *const PrimgModel) -> f32 {
+    model.as_ref().map_or(-1.0, |m| m.0.score())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_shape_count(model: *const PrimgModel) -> usize {
+    model.as_ref().map_or(0, |m| m.0.num_shapes())
+}
+
+/// Copies shape index into out.  Returns 0, or -1 if there's no such shape.
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_shape(model: *const PrimgModel, index: usize, out: *mut PrimgShape) -> i32 {
+    let model = match model.as_ref() {
+        Some(m) => &m.0,
+        None => return -1,
+    };
+    if out.is_null() || index >= model.num_shapes() {
+        return -1;
+    }
+    let (shape, color) = model.shape(index);
+    let (kind, params) = shape_params(shape);
+    *out = PrimgShape { kind, params, r: color.r(), g: color.g(), b: color.b(), a: color.a() };
+    0
+}
+
+/// The model as an SVG document, to be released with primg_string_free, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn primg_model_svg(model: *const PrimgModel) -> *mut c_char {
+    let model = match model.as_ref() {
+        Some(m) => &m.0,
+        None => return ptr::null_mut(),
+    };
+    match CString::new(model.svg()) {
+        Ok(s) => s.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn primg_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+pub fn shape_type(kind: i32) -> Option<ShapeType> {
+    match kind {
+        PRIMG_TRIANGLE => Some(ShapeType::Triangle),
+        PRIMG_ELLIPSE => Some(ShapeType::Ellipse),
+        PRIMG_RECTANGLE => Some(ShapeType::Rectangle),
+        PRIMG_ROTATED_RECTANGLE => Some(ShapeType::RotatedRectangle),
+        _ => None,
+    }
+}
+
+pub fn shape_params(shape: &Shape) -> (i32, [f32; 6]) {
+    match *shape {
+        Shape::Triangle { x1, y1, x2, y2, x3, y3 } => (PRIMG_TRIANGLE, [x1, y1, x2, y2, x3, y3]),
+        Shape::Ellipse { x, y, rx, ry } => (PRIMG_ELLIPSE, [x, y, rx, ry, 0.0, 0.0]),
+        Shape::Rectangle { x1, y1, x2, y2 } => (PRIMG_RECTANGLE, [x1, y1, x2, y2, 0.0, 0.0]),
+        Shape::RotatedRectangle { x, y, sx, sy, angle } => (PRIMG_ROTATED_RECTANGLE, [x, y, sx, sy, angle, 0.0]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // a model of a small gradient, on a thread with room for its images on the stack
+    fn with_model<F: FnOnce(*mut PrimgModel) + Send + 'static>(f: F) {
+        thread::Builder::new().stack_size(16 << 20).spawn(move || {
+            let rgba: Vec<u8> = (0..32 * 24).flat_map(|i| vec![(i % 32 * 8) as u8, (i / 32 * 10) as u8, 128, 255]).collect();
+            unsafe {
+                let model = primg_model_new(rgba.as_ptr(), 32, 24, 64, 2, 0);
+                assert!(!model.is_null());
+                f(model);
+                primg_model_free(model);
+            }
+        }).expect("wtf").join().expect("wtf");
+    }
+
+    #[test]
+    fn new_rejects_sizes_that_overflow() {
+        let rgba = [0u8; 4];
+        unsafe {
+            assert!(primg_model_new(rgba.as_ptr(), u32::MAX, u32::MAX, 64, 1, 0).is_null());
+            assert!(primg_model_new(ptr::null(), 1, 1, 64, 1, 0).is_null());
+            assert!(primg_model_new(rgba.as_ptr(), 0, 1, 64, 1, 0).is_null());
+        }
+    }
+
+    #[test]
+    fn step_rejects_invalid_arguments() {
+        with_model(|model| unsafe {
+            assert_eq!(primg_model_step(model, PRIMG_TRIANGLE, 0, 10, 1), -1);
+            assert_eq!(primg_model_step(model, PRIMG_TRIANGLE, 128, 0, 1), -1);
+            assert_eq!(primg_model_step(model, 7, 128, 10, 1), -1);
+            assert_eq!(primg_model_step(ptr::null_mut(), PRIMG_TRIANGLE, 128, 10, 1), -1);
+            assert_eq!(primg_model_step(model, PRIMG_TRIANGLE, 128, 10, 1), 0);
+        });
+    }
+
+    #[test]
+    fn step_fails_when_a_worker_panics() {
+        with_model(|model| unsafe {
+            // alpha 0 makes the workers divide by zero
+            let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                (*model).0.step(ShapeType::Triangle, 0, 10, 1, Constraints::default());
+            }));
+            assert!(result.is_err());
+            assert_eq!(primg_model_step(model, PRIMG_TRIANGLE, 128, 10, 1), 0);
+        });
+    }
+}
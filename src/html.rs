@@ -0,0 +1,153 @@
+// A self-contained web page that draws the shapes on a canvas, with play/pause and a slider over
+// how many shapes are shown.  Shapes are drawn exactly as Model::svg lays them out.
+
+use core::Color;
+use shape::Shape;
+use stream;
+use util;
+
+pub fn page(width: usize, height: usize, scale: f32, bg: &Color, shapes: &[(&Shape, Color)]) -> String {
+    let shapes: Vec<String> = shapes.iter()
+        .map(|&(shape, color)| {
+            let (kind, params) = stream::params(shape);
+            let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+            format!("[{},[{}],\"{}\",{}]", kind, params.join(","), util::hex(&color), color.a())
+        })
+        .collect();
+    let picture = format!("{{\"scale\":{},\"background\":\"{}\",\"shapes\":[\n{}\n]}}",
+                          scale, util::hex(bg), shapes.join(",\n"));
+    TEMPLATE
+        .replace("{width}", &width.to_string())
+        .replace("{height}", &height.to_string())
+        .replace("{count}", &shapes.len().to_string())
+        .replace("{picture}", &picture)
+}
+
+// shapes are [kind, params, color, alpha] with kind and params as in stream.rs
+const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>primg</title>
+<style>
+body { margin: 0; padding: 16px; font: 14px sans-serif; background: #eee; }
+#picture { max-width: {width}px; }
+canvas { display: block; width: 100%; height: auto; box-shadow: 0 1px 4px rgba(0, 0, 0, 0.3); }
+.controls { display: flex; align-items: center; margin-top: 12px; }
+.controls > * { margin-right: 12px; }
+#slider { flex: 1; }
+#play { width: 5em; }
+</style>
+</head>
+<body>
+<div id="picture">
+<canvas id="canvas" width="{width}" height="{height}"></canvas>
+<div class="controls">
+<button id="play">Pause</button>
+<input id="slider" type="range" min="0" max="{count}" value="0">
+<span id="count"></span>
+</div>
+</div>
+<script>
+var picture = {picture};
+
+var canvas = document.getElementById("canvas");
+var ctx = canvas.getContext("2d");
+var slider = document.getElementById("slider");
+var button = document.getElementById("play");
+var count = document.getElementById("count");
+var total = picture.shapes.length;
+// number of shapes on the canvas, and the pending animation frame while playing
+var shown = 0;
+var playing = null;
+// playback takes about 5 seconds at 60 frames a second, however many shapes there are
+var perFrame = Math.max(1, Math.ceil(total / 300));
+
+function drawShape(s) {
+    var p = s[1];
+    // same transform as the svg: scaled up, with pixels centered on whole coordinates
+    ctx.setTransform(picture.scale, 0, 0, picture.scale, picture.scale / 2, picture.scale / 2);
+    ctx.fillStyle = s[2];
+    ctx.globalAlpha = s[3] / 255;
+    ctx.beginPath();
+    switch (s[0]) {
+    case 0:
+        ctx.moveTo(p[0], p[1]);
+        ctx.lineTo(p[2], p[3]);
+        ctx.lineTo(p[4], p[5]);
+        ctx.closePath();
+        break;
+    case 1:
+        ctx.ellipse(p[0], p[1], Math.abs(p[2]), Math.abs(p[3]), 0, 0, 2 * Math.PI);
+        break;
+    case 2:
+        ctx.rect(p[0] - 0.5, p[1] - 0.5, p[2] - p[0] + 1, p[3] - p[1] + 1);
+        break;
+    case 3:
+        ctx.translate(p[0], p[1]);
+        ctx.rotate(p[4] * Math.PI / 180);
+        ctx.scale(p[2], p[3]);
+        ctx.rect(-0.5, -0.5, 1, 1);
+        break;
+    }
+    ctx.fill();
+}
+
+// shows the first n shapes, only drawing the new ones when going forward
+function show(n) {
+    n = Math.min(n, total);
+    if (n < shown || shown === 0) {
+        ctx.setTransform(1, 0, 0, 1, 0, 0);
+        ctx.globalAlpha = 1;
+        ctx.fillStyle = picture.background;
+        ctx.fillRect(0, 0, canvas.width, canvas.height);
+        shown = 0;
+    }
+    for (; shown < n; shown++) {
+        drawShape(picture.shapes[shown]);
+    }
+    slider.value = n;
+    count.textContent = n + " / " + total;
+}
+
+function play() {
+    if (shown >= total) {
+        show(0);
+    }
+    playing = requestAnimationFrame(frame);
+    button.textContent = "Pause";
+}
+
+function pause() {
+    cancelAnimationFrame(playing);
+    playing = null;
+    button.textContent = "Play";
+}
+
+function frame() {
+    show(Math.min(total, shown + perFrame));
+    if (shown >= total) {
+        pause();
+    } else {
+        playing = requestAnimationFrame(frame);
+    }
+}
+
+button.addEventListener("click", function () {
+    if (playing !== null) {
+        pause();
+    } else {
+        play();
+    }
+});
+slider.addEventListener("input", function () {
+    pause();
+    show(parseInt(slider.value, 10));
+});
+
+show(0);
+play();
+</script>
+</body>
+</html>
+"#;
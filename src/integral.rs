@@ -0,0 +1,173 @@
+use core::{Color, Pixels};
+use scanline::Scanline;
+
+// Prefix sums along each row of the target and current images.  The color of a shape and,
+// approximately, the change in error from drawing it follow from sums over its scanlines, so a shape
+// can be scored in time proportional to its height instead of its area.  Only the rows a new shape
+// covers need updating after it's drawn.
+#[derive(Clone)]
+pub struct RowSums {
+    w: usize,
+    // w + 1 entries per row, each the sums to the left of x of, per channel (r, g, b): t, c, c * c
+    // and t * c, where t is the target and c the current image
+    sums: Vec<[i64; 12]>,
+}
+
+const T: usize = 0;
+const C: usize = 3;
+const CC: usize = 6;
+const TC: usize = 9;
+
+impl RowSums {
+    pub fn new(target: &Pixels, current: &Pixels) -> RowSums {
+        let w = target.w;
+        let mut sums = RowSums { w, sums: vec![[0; 12]; (w + 1) * target.h] };
+        for y in 0..target.h {
+            sums.update_row(target, current, y, 0);
+        }
+        sums
+    }
+
+    // call after drawing lines on current
+    pub fn update(&mut self, target: &Pixels, current: &Pixels, lines: &[Scanline]) {
+        for line in lines {
+            self.update_row(target, current, line.y, line.x1);
+        }
+    }
+
+    fn update_row(&mut self, target: &Pixels, current: &Pixels, y: usize, x1: usize) {
+        let row = y * (self.w + 1);
+        for x in x1..self.w {
+            let t = target.get_arr(x, y);
+            let c = current.get_arr(x, y);
+            let mut s = self.sums[row + x];
+            for k in 0..3 {
+                let (t, c) = (t[k] as i64, c[k] as i64);
+                s[T + k] += t;
+                s[C + k] += c;
+                s[CC + k] += c * c;
+                s[TC + k] += t * c;
+            }
+            self.sums[row + x + 1] = s;
+        }
+    }
+
+    pub fn totals(&self, lines: &[Scanline]) -> Totals {
+        let mut totals = Totals { sums: [0; 12], count: 0 };
+        for line in lines {
+            if line.x2 < line.x1 {
+                continue;
+            }
+            let row = line.y * (self.w + 1);
+            let a = &self.sums[row + line.x1];
+            let b = &self.sums[row + line.x2 + 1];
+            for j in 0..12 {
+                totals.sums[j] += b[j] - a[j];
+            }
+            totals.count += (line.x2 - line.x1 + 1) as i64;
+        }
+        totals
+    }
+}
+
+// RowSums over a shape's scanlines
+pub struct Totals {
+    sums: [i64; 12],
+    count: i64,
+}
+
+impl Totals {
+    // same as Pixels::compute_color
+    pub fn color(&self, alpha: u8) -> Color {
+        let mut diffs = [0i64; 3];
+        let mut sums = [0i64; 3];
+        for k in 0..3 {
+            diffs[k] = self.sums[T + k] - self.sums[C + k];
+            sums[k] = self.sums[C + k];
+        }
+        Color::fit(&diffs, &sums, self.count, alpha)
+    }
+
+    // Approximate change in squared error from drawing color over the pixels.  The current image is
+    // opaque, so drawing maps each channel c to floor((r * a + c * (255 - a)) / 256).  That is taken
+    // to be p + q * c, with the floor replaced by its average effect, which makes the change a sum of
+    // the stored terms.  The floor's remainder depends on each pixel's c, so no fixed set of sums
+    // gives it exactly: each blended channel is off by up to half a level, and its squared error by
+    // less than 256.  Those mostly cancel over a shape, but the total can differ from
+    // Pixels::difference_partial, which is why this is only used when asked for.
+    pub fn error_delta(&self, color: &Color) -> f64 {
+        let a = color.a() as f64;
+        let q = (255.0 - a) / 256.0;
+        let n = self.count as f64;
+        let channels = [color.r(), color.g(), color.b()];
+        let mut total = 0.0;
+        for (k, &channel) in channels.iter().enumerate() {
+            let p = (channel as f64 * a - 127.5) / 256.0;
+            let t = self.sums[T + k] as f64;
+            let c = self.sums[C + k] as f64;
+            let cc = self.sums[CC + k] as f64;
+            let tc = self.sums[TC + k] as f64;
+            // sum of (t - p - q * c)^2 - (t - c)^2, without the t^2 terms which cancel
+            total += n * p * p + 2.0 * p * q * c + q * q * cc - 2.0 * p * t - 2.0 * q * tc - cc + 2.0 * tc;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shape::Shape;
+
+    // a noisy image, the same every run
+    fn noise(w: usize, h: usize, seed: u32) -> Pixels {
+        let mut pixels = Pixels::new(w, h);
+        let mut state = seed;
+        for y in 0..h {
+            for x in 0..w {
+                let mut channel = || {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (state >> 16) as u8
+                };
+                let color = Color::new(channel(), channel(), channel(), 255);
+                pixels.put(x, y, &color);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn totals_match_pixels() {
+        let (w, h) = (64, 48);
+        let target = noise(w, h, 1);
+        let current = noise(w, h, 2);
+        let sums = RowSums::new(&target, &current);
+        let mut buf = Scanline::buffer(h);
+        let shape = Shape::Rectangle { x1: 5.0, y1: 7.0, x2: 40.0, y2: 30.0 };
+        let lines = shape.rasterize(w, h, &mut buf);
+        let totals = sums.totals(lines);
+        for &alpha in &[1, 64, 128, 255] {
+            // the color is exact
+            let color = totals.color(alpha);
+            let expected = current.compute_color(&target, lines, alpha);
+            assert_eq!((color.r(), color.g(), color.b(), color.a()),
+                       (expected.r(), expected.g(), expected.b(), expected.a()));
+
+            // and the change in error within the documented bound
+            let mut after = current.clone();
+            after.draw_lines(&color, lines);
+            let mut exact = 0i64;
+            for line in lines {
+                for x in line.x1..line.x2 + 1 {
+                    let (t, c, a) = (target.get_arr(x, line.y), current.get_arr(x, line.y), after.get_arr(x, line.y));
+                    for k in 0..3 {
+                        let (t, c, a) = (t[k] as i64, c[k] as i64, a[k] as i64);
+                        exact += (t - a) * (t - a) - (t - c) * (t - c);
+                    }
+                }
+            }
+            let bound = 256.0 * 3.0 * totals.count as f64;
+            assert!((totals.error_delta(&color) - exact as f64).abs() < bound);
+        }
+    }
+}
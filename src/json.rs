@@ -0,0 +1,36 @@
+// JSON for shape events, shared by the server and the command line's --json.  Small enough not to
+// need a serializer.
+
+use core::Color;
+use shape::Shape;
+use stream;
+use util::hex;
+
+// by stream kind
+const SHAPE_NAMES: [&str; 4] = ["triangle", "ellipse", "rectangle", "rotated-rectangle"];
+
+// the members, without braces, describing shape index as found by Model::step: its shape type,
+// params (as in stream.rs), color, alpha, and the score after adding it
+pub fn shape_fields(index: usize, shape: &Shape, color: &Color, score: f32) -> String {
+    let (kind, params) = stream::params(shape);
+    let params: Vec<String> = params.iter().map(|p| p.to_string()).collect();
+    format!("\"index\":{},\"shape\":\"{}\",\"params\":[{}],\"color\":\"{}\",\"alpha\":{},\"score\":{}",
+            index, SHAPE_NAMES[kind as usize], params.join(","), hex(color), color.a(), score)
+}
+
+// s as the inside of a JSON string
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -4,18 +4,41 @@ extern crate rand;
 extern crate threadpool;
 
 mod core;
+mod coverage;
+mod ffi;
+mod html;
+mod integral;
+mod json;
 mod model;
+mod optimizer;
+mod palette;
+mod refine;
 mod scanline;
+mod schedule;
+mod server;
 mod shape;
+mod simd;
 mod state;
+pub mod stream;
+mod svg;
 mod util;
+mod vector;
 mod worker;
 
-pub use shape::ShapeType;
+pub use core::{Color, ColorMode};
+pub use optimizer::OptimizerType;
+pub use palette::Palette;
+pub use schedule::{Decay, Schedule};
+pub use server::serve;
+pub use shape::{Constraints, Shape, ShapeType};
+pub use svg::SvgOptions;
 
-use std::io::Write;
+use std::io;
+use std::io::{BufWriter, Write};
 use std::fs::File;
+use std::time::Instant;
 
+use core::Pixels;
 use model::Model;
 
 const SIZE: usize = 256;
@@ -23,111 +46,373 @@ const SIZE: usize = 256;
 pub fn run(config: Config) {
 //    println!("{:?}", config);
 
+    let start = Instant::now();
     let img = util::load_image(config.in_path.as_ref()).expect("couldn't load image");
+    let palette = match config.palette_size {
+        Some(k) => {
+            let src = match config.palette_image {
+                Some(ref path) => util::load_image(path).expect("couldn't load palette image"),
+                None => img.clone(),
+            };
+            let src = util::scaled_to_area(src, SIZE * SIZE).to_rgba();
+            Some(Palette::extract(&Pixels::from(src), k))
+        }
+        None => config.palette,
+    };
     let cpus = num_cpus::get_physical();
-    let mut model = Model::new(img, cpus, config.out_size);
-    for _ in 0..config.num_shapes {
-        model.step(config.shape_type, config.alpha, 1000, config.m);
+    let mut model = Model::new(img, cpus, config.out_size, palette, config.mode, config.optimizer.build(), config.prescreen);
+    model.fast_rectangles = config.fast_rectangles;
+    let mut json = config.json;
+    for i in 0..config.num_shapes {
+        let constraints = match config.schedule {
+            Some(ref schedule) => schedule.constraints(&config.constraints, i, config.num_shapes),
+            None => config.constraints,
+        };
+        model.step(config.shape_type, config.alpha, 1000, config.m, constraints);
+        if json {
+            // seconds since the run started
+            let elapsed = start.elapsed().as_secs_f64();
+            let (shape, color) = model.shape(i as usize);
+            let fields = json::shape_fields(i as usize, shape, &color, model.score());
+            // whoever was reading may have stopped, e.g. head, but OUTFILE still gets saved
+            json = writeln!(io::stdout().lock(), "{{{},\"elapsed\":{:.3}}}", fields, elapsed).is_ok();
+        }
+    }
+    for _ in 0..config.refine {
+        model.refine(100, config.constraints);
+    }
+    if config.prune_threshold.is_some() || config.prune_to.is_some() {
+        model.prune(config.prune_threshold.unwrap_or(0.0), config.prune_to);
     }
-    if config.out_path.ends_with(".svg") {
-        let mut file = File::create(&config.out_path).unwrap();
-        file.write_all(model.svg().as_bytes()).unwrap();
+    if let Some(ref out_path) = config.out_path {
+        save(&model, out_path, config.antialias, &config.svg);
+    }
+}
+
+fn save(model: &Model, out_path: &str, antialias: usize, svg: &Option<SvgOptions>) {
+    if out_path.ends_with(".svg") {
+        let svg = match *svg {
+            Some(ref options) => model.svg_optimized(options),
+            None => model.svg(),
+        };
+        let mut file = File::create(out_path).unwrap();
+        file.write_all(svg.as_bytes()).unwrap();
+    } else if out_path.ends_with(".pdf") {
+        let mut file = File::create(out_path).unwrap();
+        file.write_all(&model.pdf()).unwrap();
+    } else if out_path.ends_with(".eps") {
+        let mut file = File::create(out_path).unwrap();
+        file.write_all(model.eps().as_bytes()).unwrap();
+    } else if out_path.ends_with(".html") {
+        let mut file = File::create(out_path).unwrap();
+        file.write_all(model.html().as_bytes()).unwrap();
+    } else if out_path.ends_with(".shapes") {
+        let file = BufWriter::new(File::create(out_path).unwrap());
+        model.write_stream(file, stream::Format::Text).unwrap();
+    } else if out_path.ends_with(".shapes.bin") {
+        let file = BufWriter::new(File::create(out_path).unwrap());
+        model.write_stream(file, stream::Format::Binary).unwrap();
     } else {
-        model.save_rasterized(&config.out_path).expect("wtf");
+        model.save_rasterized(out_path, antialias).expect("wtf");
     }
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub in_path: String,
-    pub out_path: String,
+    // nothing is saved if None
+    pub out_path: Option<String>,
     pub num_shapes: u32,
     pub shape_type: ShapeType,
     pub out_size: usize,
     pub alpha: u8,
     pub m: u8,
+    pub palette: Option<Palette>,
+    pub palette_size: Option<usize>,
+    pub palette_image: Option<String>,
+    pub mode: ColorMode,
+    pub optimizer: OptimizerType,
+    pub prescreen: usize,
+    // score rectangles approximately from row sums, see Model::fast_rectangles
+    pub fast_rectangles: bool,
+    pub constraints: Constraints,
+    pub schedule: Option<Schedule>,
+    pub refine: u32,
+    pub prune_threshold: Option<f32>,
+    pub prune_to: Option<usize>,
+    pub antialias: usize,
+    // write compact SVG, if set
+    pub svg: Option<SvgOptions>,
+    // print each shape to stdout as a line of JSON as it's found
+    pub json: bool,
 }
 
 #[cfg(target_os="android")]
 #[allow(non_snake_case)]
-pub mod android {
+mod android {
     extern crate jni;
 
     use super::*;
+    use image::{DynamicImage, RgbaImage};
+    use std::panic;
+    use std::ptr;
+    use std::sync::Mutex;
     use self::jni::JNIEnv;
-    use self::jni::objects::{JClass, JString, JValue};
-    use self::jni::sys::{jstring, jint, jobject};
+    use self::jni::objects::{JByteBuffer, JClass, JObject, JValue};
+    use self::jni::sys::{jfloat, jfloatArray, jint, jintArray, jlong, jobject, jstring};
+
+    const ILLEGAL_ARGUMENT: &str = "java/lang/IllegalArgumentException";
+    const RUNTIME_EXCEPTION: &str = "java/lang/RuntimeException";
 
-    static mut CONFIG_OPT: Option<Config> = None;
-    static mut MODEL_OPT: Option<Model> = None;
+    // floats per shape returned by jniStep: kind (ffi::PRIMG_*), the 6 params of ffi::PrimgShape,
+    // then r, g, b, a
+    const SHAPE_FLOATS: usize = 11;
+
+    // One image being processed.  Java holds it as a long handle from jniInit* until jniFree, so any
+    // number of sessions can run at once.  Calls on the same session are serialized by the mutex.
+    struct Session {
+        model: Model,
+        shape_type: ShapeType,
+        alpha: u8,
+        m: u8,
+    }
 
+    // pixels is a direct ByteBuffer of width x height RGBA pixels, 4 bytes each with no row padding
+    // (what Bitmap.copyPixelsToBuffer writes for ARGB_8888).  prescreen is as in primg_model_new.
     #[no_mangle]
-    pub unsafe extern fn Java_com_github_larryng_primage_jni_Primg_jniInit(
-        env: JNIEnv, _: JClass, img_path: JString, shape_type: jint, m: jint) -> jobject {
-
-        let in_path: String = env.get_string(img_path).expect("wtf").into();
-        let out_path = String::from("");
-        let shape_type = match shape_type {
-            0 => ShapeType::Triangle,
-            1 => ShapeType::Ellipse,
-            2 => ShapeType::Rectangle,
-            3 => ShapeType::RotatedRectangle,
-            _ => unreachable!(),
+    pub unsafe extern "C" fn Java_com_github_larryng_primage_jni_Primg_jniInitBuffer(
+        env: JNIEnv, _: JClass, pixels: JByteBuffer, width: jint, height: jint,
+        shape_type: jint, alpha: jint, out_size: jint, m: jint, prescreen: jint) -> jobject {
+
+        if pixels.into_inner().is_null() || width < 1 || height < 1 {
+            return throw(&env, ILLEGAL_ARGUMENT, "no pixels");
+        }
+        // capacity is -1 for a buffer that isn't direct
+        let data = match env.get_direct_buffer_capacity(pixels) {
+            Ok(capacity) if capacity >= 0 => env.get_direct_buffer_address(pixels).expect("wtf"),
+            _ => return throw(&env, ILLEGAL_ARGUMENT, "pixels must be a direct ByteBuffer"),
         };
-        let out_size = 512;
-        let alpha = 128;
-        let num_shapes = 42;
-        let m = m as u8;
-        let config = Config {
-            in_path,
-            out_path,
-            num_shapes,
-            shape_type,
-            out_size,
-            alpha,
-            m
+        let n = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(4));
+        let n = match n {
+            Some(n) if n <= data.len() => n,
+            _ => return throw(&env, ILLEGAL_ARGUMENT, "ByteBuffer is smaller than width * height * 4"),
         };
+        let img = RgbaImage::from_raw(width as u32, height as u32, data[..n].to_vec()).expect("wtf");
+        start(&env, img, shape_type, alpha, out_size, m, prescreen)
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_larryng_primage_jni_Primg_jniInitBitmap(
+        env: JNIEnv, _: JClass, bitmap: JObject,
+        shape_type: jint, alpha: jint, out_size: jint, m: jint, prescreen: jint) -> jobject {
+
+        if bitmap.is_null() {
+            return throw(&env, ILLEGAL_ARGUMENT, "no bitmap");
+        }
+        match read_bitmap(&env, bitmap) {
+            Ok(img) => start(&env, img, shape_type, alpha, out_size, m, prescreen),
+            // an exception is pending
+            Err(_) => ptr::null_mut(),
+        }
+    }
 
-        let img = util::load_image(config.in_path.as_ref()).expect("couldn't load image");
-        let img = util::scaled_to_area(img, SIZE * SIZE);
-        let cpus = num_cpus::get_physical();
+    // Copies the pixels out a row at a time with Bitmap.getPixels, which unpremultiplies them and
+    // works for any config, without holding a second copy of the whole bitmap on the Java heap.
+    fn read_bitmap(env: &JNIEnv, bitmap: JObject) -> jni::errors::Result<RgbaImage> {
+        let w = env.call_method(bitmap, "getWidth", "()I", &[])?.i()?;
+        let h = env.call_method(bitmap, "getHeight", "()I", &[])?.i()?;
+        let row: jintArray = env.new_int_array(w)?;
+        let mut argb = vec![0; w as usize];
+        let mut data = Vec::with_capacity(w as usize * h as usize * 4);
+        for y in 0..h {
+            let args = [JValue::Object(JObject::from(row)), JValue::Int(0), JValue::Int(w),
+                JValue::Int(0), JValue::Int(y), JValue::Int(w), JValue::Int(1)];
+            env.call_method(bitmap, "getPixels", "([IIIIIII)V", &args)?;
+            env.get_int_array_region(row, 0, &mut argb)?;
+            for &p in &argb {
+                data.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8, (p >> 24) as u8]);
+            }
+        }
+        let _ = env.delete_local_ref(JObject::from(row));
+        Ok(RgbaImage::from_raw(w as u32, h as u32, data).expect("wtf"))
+    }
 
-        let model = Model::new(img, cpus, config.out_size);
+    // Makes a session and returns a PrimgInitResult(long handle, Object debug, int w, int h, int bgColor),
+    // w and h being the working image size that shape coordinates are relative to.
+    unsafe fn start(env: &JNIEnv, img: RgbaImage, shape_type: jint, alpha: jint, out_size: jint, m: jint,
+                    prescreen: jint) -> jobject {
+        let shape_type = match ffi::shape_type(shape_type) {
+            Some(t) => t,
+            None => return throw(env, ILLEGAL_ARGUMENT, &format!("unknown shape type {}", shape_type)),
+        };
+        if !(1..=255).contains(&alpha) {
+            return throw(env, ILLEGAL_ARGUMENT, "alpha must be between 1-255");
+        }
+        if out_size < 1 {
+            return throw(env, ILLEGAL_ARGUMENT, "size must be positive");
+        }
+        if !(1..=255).contains(&m) {
+            return throw(env, ILLEGAL_ARGUMENT, "quality must be between 1-255");
+        }
+        if prescreen < 0 {
+            return throw(env, ILLEGAL_ARGUMENT, "prescreen must not be negative");
+        }
+
+        let model = match catching(env, || {
+            let cpus = num_cpus::get_physical();
+            Model::new(DynamicImage::ImageRgba8(img), cpus, out_size as usize, None, ColorMode::Color,
+                       OptimizerType::HillClimb.build(), prescreen as usize)
+        }) {
+            Some(model) => model,
+            None => return ptr::null_mut(),
+        };
 
-        let class = env.find_class("com/github/larryng/primage/jni/PrimgInitResult").expect("couldn't load class");
-        let constructor = env.get_method_id(class, "<init>", "(Ljava/lang/Object;III)V").expect("couldn't get constructor");
         let debug: String = format!("cpus: get={}, physical={}", num_cpus::get(), num_cpus::get_physical());
-        let debug = JValue::Object(env.new_string(debug).unwrap().into());
+        let debug = JValue::Object(env.new_string(debug).expect("wtf").into());
         let w = JValue::Int(model.w as i32);
         let h = JValue::Int(model.h as i32);
         let color = JValue::Int(model.bg.to_argb_i32());
-        let args = &[debug, w, h, color];
-        let obj = env.new_object_by_id(class, constructor, &args[..]).expect("couldn't make PrimgInitResult").into_inner();
+        let session = Session { model, shape_type, alpha: alpha as u8, m: m as u8 };
+        let handle = Box::into_raw(Box::new(Mutex::new(session))) as jlong;
+        let args = &[JValue::Long(handle), debug, w, h, color];
+        match env.new_object("com/github/larryng/primage/jni/PrimgInitResult", "(JLjava/lang/Object;III)V", &args[..]) {
+            Ok(obj) => obj.into_inner(),
+            Err(_) => {
+                drop(Box::from_raw(handle as *mut Mutex<Session>));
+                if !env.exception_check().unwrap_or(false) {
+                    throw::<()>(env, RUNTIME_EXCEPTION, "couldn't make PrimgInitResult");
+                }
+                ptr::null_mut()
+            }
+        }
+    }
+
+    // Adds count shapes and returns them as count * SHAPE_FLOATS floats.  If progress isn't null its
+    // void onProgress(int done, int count, float score) is called after each shape; it must not call
+    // back into the same session.  If it throws, the exception is left pending and null is returned,
+    // though the shapes added so far stay in the session.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_larryng_primage_jni_Primg_jniStep(
+        env: JNIEnv, _: JClass, handle: jlong, count: jint, progress: JObject) -> jfloatArray {
 
-        MODEL_OPT = Some(model);
-        CONFIG_OPT = Some(config);
+        if count < 0 {
+            return throw(&env, ILLEGAL_ARGUMENT, "count must not be negative");
+        }
+        let session = match session(&env, handle) {
+            Some(session) => session,
+            None => return ptr::null_mut(),
+        };
+        let mut session = match session.lock() {
+            Ok(session) => session,
+            Err(_) => return throw(&env, RUNTIME_EXCEPTION, "session failed in an earlier call"),
+        };
+        let session = &mut *session;
+        let (shape_type, alpha, m) = (session.shape_type, session.alpha, session.m);
+        let model = &mut session.model;
+        let first = model.num_shapes();
+        let stepped = catching(&env, || {
+            for i in 0..count {
+                model.step(shape_type, alpha, 1000, m, Constraints::default());
+                if !progress.is_null() {
+                    let args = [JValue::Int(i + 1), JValue::Int(count), JValue::Float(model.score())];
+                    if env.call_method(progress, "onProgress", "(IIF)V", &args).is_err() {
+                        return false;
+                    }
+                }
+            }
+            true
+        });
+        if stepped != Some(true) {
+            return ptr::null_mut();
+        }
 
-        obj
+        let mut floats: Vec<jfloat> = Vec::with_capacity(count as usize * SHAPE_FLOATS);
+        for i in first..model.num_shapes() {
+            let (shape, color) = model.shape(i);
+            let (kind, params) = ffi::shape_params(shape);
+            floats.push(kind as f32);
+            floats.extend_from_slice(&params);
+            floats.extend_from_slice(&[color.r() as f32, color.g() as f32, color.b() as f32, color.a() as f32]);
+        }
+        let array = match env.new_float_array(floats.len() as jint) {
+            Ok(array) => array,
+            Err(_) => return ptr::null_mut(),
+        };
+        match env.set_float_array_region(array, 0, &floats) {
+            Ok(_) => array,
+            Err(_) => ptr::null_mut(),
+        }
     }
 
+    // the session's shapes so far as an SVG document, scaled to its output size
     #[no_mangle]
-    pub unsafe extern fn Java_com_github_larryng_primage_jni_Primg_jniStep(
-        env: JNIEnv, _: JClass) -> jstring {
+    pub unsafe extern "C" fn Java_com_github_larryng_primage_jni_Primg_jniSvg(
+        env: JNIEnv, _: JClass, handle: jlong) -> jstring {
 
-        let config = match CONFIG_OPT {
-            Some(ref c) => c,
-            None => unreachable!(),
+        let session = match session(&env, handle) {
+            Some(session) => session,
+            None => return ptr::null_mut(),
         };
-
-        let model = match MODEL_OPT {
-            Some(ref mut m) => m,
-            None => unreachable!(),
+        let svg = match session.lock() {
+            Ok(session) => session.model.svg(),
+            Err(_) => return throw(&env, RUNTIME_EXCEPTION, "session failed in an earlier call"),
         };
+        match env.new_string(svg) {
+            Ok(s) => s.into_inner(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    // releases a session.  the handle must not be used afterwards.
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_github_larryng_primage_jni_Primg_jniFree(
+        env: JNIEnv, _: JClass, handle: jlong) {
 
-        let (shape, color) = model.step(config.shape_type, config.alpha, 1000, config.m);
+        if session(&env, handle).is_some() {
+            drop(Box::from_raw(handle as *mut Mutex<Session>));
+        }
+    }
+
+    unsafe fn session<'a>(env: &JNIEnv, handle: jlong) -> Option<&'a Mutex<Session>> {
+        let session = (handle as *const Mutex<Session>).as_ref();
+        if session.is_none() {
+            throw::<()>(env, ILLEGAL_ARGUMENT, "invalid session handle");
+        }
+        session
+    }
+
+    // throws a new exception of the given class, returning a null value for the native method
+    fn throw<T: Null>(env: &JNIEnv, class: &str, msg: &str) -> T {
+        let _ = env.throw_new(class, msg);
+        T::null()
+    }
 
-        let s = format!("{}:{}", shape.serialize(), color.to_argb_i32());
+    // runs f, turning a panic into a RuntimeException
+    fn catching<T, F: FnOnce() -> T>(env: &JNIEnv, f: F) -> Option<T> {
+        match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                let msg = match e.downcast_ref::<&str>() {
+                    Some(s) => String::from(*s),
+                    None => e.downcast_ref::<String>().cloned().unwrap_or_else(|| String::from("primg failed")),
+                };
+                throw::<()>(env, RUNTIME_EXCEPTION, &msg);
+                None
+            }
+        }
+    }
+
+    trait Null {
+        fn null() -> Self;
+    }
+
+    impl Null for () {
+        fn null() {}
+    }
 
-        env.new_string(s).unwrap().into_inner()
+    impl<T> Null for *mut T {
+        fn null() -> *mut T {
+            ptr::null_mut()
+        }
     }
 }
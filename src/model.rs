@@ -2,17 +2,29 @@ use super::SIZE;
 
 use image;
 use image::DynamicImage;
+use rand::StdRng;
 use std::io;
-use std::sync::{Arc, RwLock};
+use std::io::Write;
+use std::sync::{Arc, PoisonError, RwLock};
 use std::sync::mpsc;
 use std::cmp;
 use threadpool::ThreadPool;
 
-use core::{Color, Pixels};
-use shape::{Shape, ShapeType};
+use core::{Color, ColorMode, Pixels};
+use coverage::Coverage;
+use html;
+use integral::RowSums;
+use optimizer::Optimizer;
+use palette::Palette;
+use refine::{row_span, Composite};
+use shape::{Constraints, Shape, ShapeType};
 use scanline::Scanline;
+use stream::{Encoder, Format, Header};
+use svg;
+use svg::SvgOptions;
 use util;
-use worker::Worker;
+use vector;
+use worker::{Screen, Worker};
 
 pub struct Model {
     n_workers: usize,
@@ -22,98 +34,330 @@ pub struct Model {
     pub sh: usize,
     scale: f32,
     pub bg: Color,
+    // Score rectangles from row sums, which is much faster but approximate, see
+    // integral::Totals::error_delta.  Other shapes are always scored exactly.
+    pub fast_rectangles: bool,
+    mode: ColorMode,
     target: Arc<Pixels>,
-    current: Arc<RwLock<Pixels>>,
+    current: Arc<Pixels>,
+    // only kept while placing rectangles
+    row_sums: Option<Arc<RowSums>>,
+    // half size target and current for screening candidates, if enabled, and the score between them
+    small_target: Option<Arc<Pixels>>,
+    small_current: Option<Arc<Pixels>>,
+    small_score: f32,
+    buffer: Pixels,
+    palette: Option<Arc<Palette>>,
     score: f32,
     shapes: Vec<Shape>,
     colors: Vec<Color>,
     workers: Vec<Arc<RwLock<Worker>>>,
+    optimizer: Arc<dyn Optimizer>,
     pool: ThreadPool,
     scanlines: Vec<Scanline>,
 }
 
 impl Model {
-    pub fn new(img: DynamicImage, n_workers: usize, out_size: usize) -> Model {
+    // prescreen is the number of random candidates in each batch that are promoted from screening at
+    // half resolution to full resolution, or 0 to score every candidate at full resolution.
+    pub fn new(img: DynamicImage, n_workers: usize, out_size: usize, palette: Option<Palette>, mode: ColorMode,
+               optimizer: Arc<dyn Optimizer>, prescreen: usize) -> Model {
         let img = util::scaled_to_area(img, SIZE * SIZE).to_rgba();
-        let target = Pixels::from(img);
+        let mut target = Pixels::from(img);
+        if mode.is_monochrome() {
+            // with r = g = b the optimal colors stay gray, so only luminance is fitted
+            target.desaturate();
+        }
         let w = target.w;
         let h = target.h;
         let bigger = cmp::max(w, h);
         let scale = out_size as f32 / bigger as f32;
         let sw = util::scale_dimen(w as i32, scale) as usize;
         let sh = util::scale_dimen(h as i32, scale) as usize;
-        let bg = target.average_color();
+        let mut bg = target.average_color();
+        if let Some(ref palette) = palette {
+            bg = palette.nearest(&bg);
+        }
         let mut current = Pixels::new(w, h);
         current.erase(&bg);
         let score = Pixels::difference_full(&target, &current);
         let target = Arc::new(target);
-        let current = Arc::new(RwLock::new(current));
+        let current = Arc::new(current);
+        let fast_rectangles = false;
+        let row_sums = None;
+        let (small_target, small_current, small_score) = if prescreen > 0 && w >= 2 && h >= 2 {
+            let (target, current) = (target.half(), current.half());
+            let score = Pixels::difference_full(&target, &current);
+            (Some(Arc::new(target)), Some(Arc::new(current)), score)
+        } else {
+            (None, None, 0.0)
+        };
+        let buffer = Pixels::new(w, h);
+        let palette = palette.map(Arc::new);
         let shapes = Vec::new();
         let colors = Vec::new();
-        let workers = (0..n_workers).map(|_| Arc::new(RwLock::new(Worker::new(target.clone(), current.clone())))).collect();
+        let workers = (0..n_workers).map(|_| {
+            let screen = small_target.as_ref().map(|t| Screen::new(t.clone(), prescreen));
+            Arc::new(RwLock::new(Worker::new(target.clone(), palette.clone(), screen)))
+        }).collect();
         let pool = ThreadPool::new(n_workers);
         let scanlines = Scanline::buffer(h);
-        Model { n_workers, w, h, sw, sh, scale, bg, target, current, score, shapes, colors, workers, pool, scanlines }
+        Model { n_workers, w, h, sw, sh, scale, bg, fast_rectangles, mode, target, current, row_sums, small_target, small_current, small_score, buffer, palette, score, shapes, colors, workers, optimizer, pool, scanlines }
     }
 
-    pub fn step(&mut self, t: ShapeType, a: u8, n: u32, m: u8) -> (Shape, Color) {
+    pub fn step(&mut self, t: ShapeType, a: u8, n: u32, m: u8, c: Constraints) -> (Shape, Color) {
         let (tx, rx) = mpsc::channel();
 
         let score = self.score;
+        let small = self.small_current.clone().map(|current| (current, self.small_score));
         let m = cmp::max(1, m as usize / self.n_workers) as u8;
+        self.optimizer.reset();
+        match t {
+            ShapeType::Rectangle if self.fast_rectangles => {
+                if self.row_sums.is_none() {
+                    self.row_sums = Some(Arc::new(RowSums::new(&self.target, &self.current)));
+                }
+            }
+            _ => self.row_sums = None,
+        }
         for worker in &self.workers {
             let worker = worker.clone();
+            let current = self.current.clone();
+            let row_sums = self.row_sums.clone();
+            let small = small.clone();
+            let optimizer = self.optimizer.clone();
             let tx = tx.clone();
             self.pool.execute(move || {
-                let mut worker = worker.write().unwrap();
-                worker.init(score);
-                let mut state = worker.best_hill_climb_state(t, a, n, m);
+                // init resets everything a step uses, so a worker that panicked in an earlier step is
+                // fine to use again
+                let mut worker = worker.write().unwrap_or_else(PoisonError::into_inner);
+                worker.init(current, row_sums, small, score, c);
+                let mut state = optimizer.optimize(&mut worker, t, a, n, m);
                 let energy = state.energy(&mut worker);
+                // release the snapshots so that add can update them in place
+                worker.release();
                 tx.send((state, energy)).unwrap();
             });
         }
+        // add updates the half image in place, which copies it if anything else still holds it
+        drop(small);
 
-        let (state, energy) = rx.recv().unwrap();
-        let mut best_state = state;
-        let mut best_energy = energy;
+        // A worker that panics drops its sender without sending, so once every sender is gone there are
+        // fewer results than workers.  That fails here rather than waiting forever.
+        drop(tx);
+        let mut results = rx.iter();
+        let (mut best_state, mut best_energy) = results.next().expect("worker panicked");
         let mut count = 1;
-        for (state, energy) in rx {
+        for (state, energy) in results {
             count += 1;
             if energy < best_energy {
                 best_state = state;
                 best_energy = energy;
             }
-            if count >= self.n_workers {
-                break;
-            }
         }
+        assert_eq!(count, self.n_workers, "worker panicked");
 //        println!("adding {:?}", best_state.shape);
         self.add(best_state.shape, best_state.alpha)
     }
 
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+
+    pub fn num_shapes(&self) -> usize {
+        self.shapes.len()
+    }
+
+    // the i-th shape and its output color
+    pub fn shape(&self, i: usize) -> (&Shape, Color) {
+        (&self.shapes[i], self.mode.apply(&self.colors[i]))
+    }
+
+    // every shape with its color as output, in the order added
+    fn output_shapes(&self) -> Vec<(&Shape, Color)> {
+        (0..self.shapes.len()).map(|i| self.shape(i)).collect()
+    }
+
     pub fn add(&mut self, shape: Shape, alpha: u8) -> (Shape, Color) {
-        let mut current = self.current.write().unwrap();
-        let before = current.clone();
         let lines = &shape.rasterize(self.w, self.h, &mut self.scanlines);
-        let color = current.compute_color(&self.target, lines, alpha);
-        current.draw_lines(&color, &lines);
-        let score = Pixels::difference_partial(&self.target, &before, &current, self.score, lines);
+        let current = Arc::make_mut(&mut self.current);
+        let color = compute_color(&self.target, current, &self.palette, lines, alpha);
+        // only the covered lines of the image before drawing are needed to score the change
+        self.buffer.copy_lines(current, lines);
+        current.draw_lines(&color, lines);
+        let score = Pixels::difference_partial(&self.target, &self.buffer, current, self.score, lines);
+        if let Some(ref mut row_sums) = self.row_sums {
+            Arc::make_mut(row_sums).update(&self.target, current, lines);
+        }
+        if let Some(ref mut small) = self.small_current {
+            let small = Arc::make_mut(small);
+            let delta = small.update_half(current, self.small_target.as_ref().expect("wtf"), lines);
+            self.small_score = rescore(self.small_score, delta, small.w * small.h * 4);
+        }
         self.shapes.push(shape.clone());
         self.colors.push(color);
         self.score = score;
-        (shape, color)
+        (shape, self.mode.apply(&color))
+    }
+
+    // Re-optimizes each placed shape in turn.  With the shapes above it left in place, the shape is
+    // hill climbed (and its color recomputed) against the canvas below it, keeping changes that lower
+    // the total score.
+    pub fn refine(&mut self, max_age: i32, c: Constraints) {
+        let mut rng = StdRng::new().expect("wtf");
+        let mut buf = Scanline::buffer(self.h);
+        let mut layers = self.layers();
+        let mut below = Pixels::new(self.w, self.h);
+        below.erase(&self.bg);
+        let mut composite = Composite::new(self.target.clone(), (*self.current).clone());
+
+        for i in 0..self.shapes.len() {
+            let alpha = self.colors[i].a();
+            let mut age = 0;
+            while age < max_age {
+                let mut shape = self.shapes[i].clone();
+                // the first try only recomputes the color
+                if age > 0 {
+                    shape.mutate(self.w, self.h, &c, &mut rng);
+                }
+                age += 1;
+                let lines = shape.rasterize(self.w, self.h, &mut buf);
+                let (y1, y2) = match row_span(lines, &layers[i]) {
+                    Some(span) => span,
+                    None => continue,
+                };
+                let color = compute_color(&self.target, &below, &self.palette, lines, alpha);
+                let total = composite.try_rows(&below, y1, y2, Some((lines, &color)),
+                                               &layers[i + 1..], &self.colors[i + 1..]);
+                if total < composite.total {
+                    composite.accept(y1, y2);
+                    layers[i] = lines.to_vec();
+                    self.shapes[i] = shape;
+                    self.colors[i] = color;
+                    age = 1;
+                }
+            }
+            below.draw_lines(&self.colors[i], &layers[i]);
+        }
+
+        self.score = composite.score();
+        self.set_current(composite.image);
+    }
+
+    // Removes shapes that contribute little to the score: first, in order, every shape whose removal
+    // would raise the score by less than threshold, then, while there are more than max_shapes, the
+    // shapes whose removal costs the least.  Returns the number of shapes removed.
+    pub fn prune(&mut self, threshold: f32, max_shapes: Option<usize>) -> usize {
+        let n = self.shapes.len();
+        let ni = (self.w * self.h * 4) as f32;
+        let layers = self.layers();
+        let mut below = Pixels::new(self.w, self.h);
+        below.erase(&self.bg);
+        let mut composite = Composite::new(self.target.clone(), (*self.current).clone());
+        let mut keep = vec![true; n];
+
+        for i in 0..n {
+            if let Some((y1, y2)) = row_span(&layers[i], &[]) {
+                let score = composite.score();
+                let total = composite.try_rows(&below, y1, y2, None, &layers[i + 1..], &self.colors[i + 1..]);
+                let without = (total as f32 / ni).sqrt() / 255.0;
+                if without - score >= threshold {
+                    below.draw_lines(&self.colors[i], &layers[i]);
+                    continue;
+                }
+                composite.accept(y1, y2);
+            }
+            keep[i] = false;
+        }
+        self.score = composite.score();
+        self.set_current(composite.image);
+        self.retain(&keep);
+
+        if let Some(max_shapes) = max_shapes {
+            while self.shapes.len() > max_shapes {
+                // removals interact, so only drop half of the excess between re-measurements
+                let costs = self.removal_costs();
+                let excess = self.shapes.len() - max_shapes;
+                let mut order: Vec<usize> = (0..costs.len()).collect();
+                order.sort_by_key(|&i| costs[i]);
+                let mut keep = vec![true; self.shapes.len()];
+                for &i in order.iter().take(cmp::max(1, excess.div_ceil(2))) {
+                    keep[i] = false;
+                }
+                self.retain(&keep);
+                let current = self.render();
+                self.score = Pixels::difference_full(&self.target, &current);
+                self.set_current(current);
+            }
+        }
+        n - self.shapes.len()
+    }
+
+    // how much the total error would grow without each shape
+    fn removal_costs(&self) -> Vec<i64> {
+        let layers = self.layers();
+        let mut below = Pixels::new(self.w, self.h);
+        below.erase(&self.bg);
+        let mut composite = Composite::new(self.target.clone(), (*self.current).clone());
+        let mut costs = Vec::with_capacity(layers.len());
+        for i in 0..layers.len() {
+            let cost = match row_span(&layers[i], &[]) {
+                Some((y1, y2)) => {
+                    composite.try_rows(&below, y1, y2, None, &layers[i + 1..], &self.colors[i + 1..]) - composite.total
+                }
+                None => 0,
+            };
+            costs.push(cost);
+            below.draw_lines(&self.colors[i], &layers[i]);
+        }
+        costs
+    }
+
+    fn set_current(&mut self, current: Pixels) {
+        if let Some(ref target) = self.small_target {
+            let small = current.half();
+            self.small_score = Pixels::difference_full(target, &small);
+            self.small_current = Some(Arc::new(small));
+        }
+        self.current = Arc::new(current);
+        self.row_sums = None;
+    }
+
+    fn retain(&mut self, keep: &[bool]) {
+        let mut i = 0;
+        self.shapes.retain(|_| { i += 1; keep[i - 1] });
+        let mut i = 0;
+        self.colors.retain(|_| { i += 1; keep[i - 1] });
+    }
+
+    fn layers(&self) -> Vec<Vec<Scanline>> {
+        let mut buf = Scanline::buffer(self.h);
+        self.shapes.iter()
+            .map(|shape| shape.rasterize(self.w, self.h, &mut buf).to_vec())
+            .collect()
+    }
+
+    fn render(&self) -> Pixels {
+        let mut pixels = Pixels::new(self.w, self.h);
+        pixels.erase(&self.bg);
+        let mut buf = Scanline::buffer(self.h);
+        for (shape, color) in self.shapes.iter().zip(self.colors.iter()) {
+            pixels.draw_lines(color, shape.rasterize(self.w, self.h, &mut buf));
+        }
+        pixels
     }
 
     pub fn svg(&self) -> String {
+        let bg = self.mode.apply(&self.bg);
         let mut lines = vec![];
         lines.push(format!("<svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" width=\"{}\" height=\"{}\">",
                            self.sw, self.sh));
         lines.push(format!("<rect x=\"0\" y=\"0\" width=\"{}\" height=\"{}\" fill=\"#{:02x}{:02x}{:02x}\" />",
-                           self.sw, self.sh, self.bg.r(), self.bg.g(), self.bg.b()));
+                           self.sw, self.sh, bg.r(), bg.g(), bg.b()));
         lines.push(format!("<g transform=\"scale({}) translate(0.5 0.5)\">", self.scale));
 
         for (i, shape) in self.shapes.iter().enumerate() {
-            let c = &self.colors[i];
+            let c = self.mode.apply(&self.colors[i]);
             let attrs = format!("fill=\"#{:02x}{:02x}{:02x}\" fill-opacity=\"{}\"",
                                 c.r(), c.g(), c.b(), c.a() as f32 / 255.0);
             lines.push(shape.svg(&attrs));
@@ -124,20 +368,51 @@ impl Model {
         lines.join("\n")
     }
 
-    pub fn save_rasterized(&self, path: &str) -> io::Result<()> {
+    // the same picture as svg, in fewer bytes
+    pub fn svg_optimized(&self, options: &SvgOptions) -> String {
+        svg::optimized(self.w, self.h, &self.mode.apply(&self.bg), &self.output_shapes(), options)
+    }
+
+    pub fn pdf(&self) -> Vec<u8> {
+        vector::pdf(self.sw, self.sh, self.scale, &self.mode.apply(&self.bg), &self.output_shapes())
+    }
+
+    pub fn eps(&self) -> String {
+        vector::eps(self.sw, self.sh, self.scale, &self.mode.apply(&self.bg), &self.output_shapes())
+    }
+
+    // a web page playing back the shapes, see html.rs
+    pub fn html(&self) -> String {
+        html::page(self.sw, self.sh, self.scale, &self.mode.apply(&self.bg), &self.output_shapes())
+    }
+
+    pub fn stream_header(&self) -> Header {
+        Header { w: self.w, h: self.h, bg: self.mode.apply(&self.bg) }
+    }
+
+    // the model as a shape stream, see stream.rs
+    pub fn write_stream<W: Write>(&self, out: W, format: Format) -> io::Result<()> {
+        let mut encoder = Encoder::new(out, format, &self.stream_header())?;
+        for (shape, color) in self.output_shapes() {
+            encoder.shape(shape, &color)?;
+        }
+        encoder.flush()
+    }
+
+    // samples is the anti-aliasing quality: each pixel is covered by samples x samples subpixels,
+    // 1 disables anti-aliasing.
+    pub fn save_rasterized(&self, path: &str, samples: usize) -> io::Result<()> {
         let w = self.sw;
         let h = self.sh;
         let scale = self.scale;
 //        println!("w={}, h={}, scale={}", w, h, scale);
         let mut img = vec![0; w * h * 4];
-        util::erase(&mut img, &self.bg);
-        let mut buf = Scanline::buffer(h);
+        util::erase(&mut img, &self.mode.apply(&self.bg));
 
-        for i in 0..self.shapes.len() {
-            let shape = &self.shapes[i];
-            let color = &self.colors[i];
-            let lines = shape.scaled(scale).rasterize(w, h, &mut buf);
-            util::draw_lines(&mut img, w, h, &color, lines);
+        // even with one sample, so shapes land on the same pixels as in the svg
+        let mut coverage = Coverage::new(w, h, samples);
+        for (shape, color) in self.output_shapes() {
+            coverage.draw(&mut img, shape, scale, &color);
         }
 
         image::save_buffer(path,
@@ -149,11 +424,25 @@ impl Model {
 
     // for debugging
     pub fn _save_current(&self, path: &str) -> io::Result<()> {
-        let current = self.current.read().unwrap();
         image::save_buffer(path,
-                           &current.buf,
+                           &self.current.buf,
                            self.w as u32,
                            self.h as u32,
                            image::ColorType::RGBA(8))
     }
 }
+
+// the score after the squared error over n channels changes by delta, as in Pixels::difference_partial
+fn rescore(score: f32, delta: i64, n: usize) -> f32 {
+    let total = ((score * 255.0).powi(2) * n as f32) as i64 + delta;
+    (total as f32 / n as f32).sqrt() / 255.0
+}
+
+fn compute_color(target: &Pixels, current: &Pixels, palette: &Option<Arc<Palette>>,
+                 lines: &[Scanline], alpha: u8) -> Color {
+    let color = current.compute_color(target, lines, alpha);
+    match *palette {
+        Some(ref palette) => palette.nearest(&color),
+        None => color,
+    }
+}
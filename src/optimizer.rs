@@ -0,0 +1,232 @@
+use rand::{Rng, StdRng};
+use std::sync::{Arc, Mutex};
+
+use shape::ShapeType;
+use state::State;
+use worker::Worker;
+
+// Finds a good state for a single step.  Every worker calls optimize with the same settings and the
+// model keeps the best of their results.
+pub trait Optimizer: Send + Sync {
+    // called by the model before the workers start on a step
+    fn reset(&self) {}
+
+    fn optimize(&self, worker: &mut Worker, t: ShapeType, a: u8, n: u32, m: u8) -> State;
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum OptimizerType {
+    HillClimb,
+    Annealing,
+    Population,
+}
+
+impl OptimizerType {
+    pub fn build(&self) -> Arc<dyn Optimizer> {
+        match *self {
+            OptimizerType::HillClimb => Arc::new(HillClimb { max_age: 100 }),
+            OptimizerType::Annealing => Arc::new(Annealing { steps: 1000, max_temp: 0.1, min_temp: 0.001 }),
+            OptimizerType::Population => Arc::new(Population::new(20, 15, 5)),
+        }
+    }
+}
+
+// m rounds of: best of n random states, then greedy hill climbing until max_age moves in a row fail
+// to improve.
+pub struct HillClimb {
+    pub max_age: i32,
+}
+
+impl Optimizer for HillClimb {
+    fn optimize(&self, worker: &mut Worker, t: ShapeType, a: u8, n: u32, m: u8) -> State {
+        let mut state = worker.best_random_state(t, a, n);
+        worker.hill_climb(&mut state, self.max_age);
+        let mut best_state = state.clone();
+        let mut best_energy = best_state.energy(worker);
+        for _ in 1..m {
+            state = worker.best_random_state(t, a, n);
+            worker.hill_climb(&mut state, self.max_age);
+            let energy = state.energy(worker);
+            if energy < best_energy {
+                best_energy = energy;
+                best_state.copy_from(&state);
+            }
+        }
+        best_state
+    }
+}
+
+// m rounds of: best of n random states, then simulated annealing for a fixed number of moves.
+// Temperatures are relative to how much the starting state improves on the current score, so the
+// same settings work for the first shape as well as the hundredth.
+pub struct Annealing {
+    pub steps: u32,
+    pub max_temp: f32,
+    pub min_temp: f32,
+}
+
+impl Annealing {
+    fn anneal(&self, worker: &mut Worker, state: &mut State) {
+        let gain = worker.score - state.energy(worker);
+        let scale = if gain > 0.0 { gain } else { worker.score * 1e-4 };
+        let max_temp = self.max_temp * scale;
+        let factor = (self.min_temp / self.max_temp).ln();
+
+        let mut undo = state.clone();
+        let mut best_state = state.clone();
+        let mut best_energy = best_state.energy(worker);
+        let mut previous_energy = best_energy;
+        for step in 0..self.steps {
+            let pct = step as f32 / (self.steps - 1).max(1) as f32;
+            let temp = max_temp * (factor * pct).exp();
+            state.do_move(worker, &mut undo);
+            let energy = state.energy(worker);
+            let change = energy - previous_energy;
+            if change > 0.0 && (-change / temp).exp() < worker.rng.next_f32() {
+                state.copy_from(&undo);
+            } else {
+                previous_energy = energy;
+                if energy < best_energy {
+                    best_energy = energy;
+                    best_state.copy_from(state);
+                }
+            }
+        }
+        state.copy_from(&best_state);
+    }
+}
+
+impl Optimizer for Annealing {
+    fn optimize(&self, worker: &mut Worker, t: ShapeType, a: u8, n: u32, m: u8) -> State {
+        let mut best_state = worker.best_random_state(t, a, n);
+        self.anneal(worker, &mut best_state);
+        let mut best_energy = best_state.energy(worker);
+        for _ in 1..m {
+            let mut state = worker.best_random_state(t, a, n);
+            self.anneal(worker, &mut state);
+            let energy = state.energy(worker);
+            if energy < best_energy {
+                best_energy = energy;
+                best_state = state;
+            }
+        }
+        best_state
+    }
+}
+
+// A small genetic search.  Each worker seeds a population with the best of n random states, then
+// breeds children by crossover and mutation, keeping a child if it beats the parent it replaces.
+// Every few generations workers publish their best state to a pool shared by all workers for the
+// step and adopt one from it in place of their worst, so good shapes found by one worker spread to
+// the others.
+pub struct Population {
+    pub size: usize,
+    pub generations: u32,
+    pub exchange_interval: u32,
+    shared: Mutex<Vec<State>>,
+}
+
+impl Population {
+    pub fn new(size: usize, generations: u32, exchange_interval: u32) -> Population {
+        Population { size, generations, exchange_interval, shared: Mutex::new(Vec::new()) }
+    }
+
+    fn seed(&self, worker: &mut Worker, t: ShapeType, a: u8, n: u32) -> Vec<State> {
+        let mut population = Vec::with_capacity(self.size + 1);
+        for _ in 0..n {
+            let mut state = worker.random_state(t, a);
+            state.energy(worker);
+            if population.len() < self.size {
+                population.push(state);
+            } else if state.score < population[self.size - 1].score {
+                population[self.size - 1] = state;
+            } else {
+                continue;
+            }
+            sort_by_energy(&mut population);
+        }
+        population
+    }
+
+    fn exchange(&self, population: &mut [State], rng: &mut StdRng) {
+        let mut shared = self.shared.lock().unwrap();
+        let adopted = if shared.is_empty() {
+            None
+        } else {
+            Some(shared[rng.gen_range(0, shared.len())].clone())
+        };
+        shared.push(population[0].clone());
+        sort_by_energy(&mut shared);
+        shared.truncate(self.size);
+        drop(shared);
+
+        if let Some(state) = adopted {
+            let last = population.len() - 1;
+            if state.score < population[last].score {
+                population[last] = state;
+                sort_by_energy(population);
+            }
+        }
+    }
+}
+
+impl Optimizer for Population {
+    fn reset(&self) {
+        self.shared.lock().unwrap().clear();
+    }
+
+    fn optimize(&self, worker: &mut Worker, t: ShapeType, a: u8, n: u32, m: u8) -> State {
+        let mut population = self.seed(worker, t, a, n);
+        let len = population.len();
+        let generations = self.generations * m as u32;
+        for generation in 1..generations + 1 {
+            for i in 0..len {
+                let j = (i + worker.rng.gen_range(1, len.max(2))) % len;
+                let mut shape = population[i].shape.crossover(&population[j].shape, &mut worker.rng);
+                if !worker.constraints.allows(&shape, worker.w as i32, worker.h as i32) {
+                    shape = population[i].shape.clone();
+                }
+                let mut child = State::new(shape, a);
+                if worker.rng.gen() {
+                    child.shape.mutate(worker.w, worker.h, &worker.constraints, &mut worker.rng);
+                }
+                if child.energy(worker) < population[i].score {
+                    population[i] = child;
+                }
+            }
+            sort_by_energy(&mut population);
+            if generation % self.exchange_interval == 0 {
+                self.exchange(&mut population, &mut worker.rng);
+            }
+        }
+        let mut best_state = population.swap_remove(0);
+        worker.hill_climb(&mut best_state, 100);
+        best_state
+    }
+}
+
+// best first, and NaN, e.g. from a degenerate shape, last whatever its sign
+fn sort_by_energy(states: &mut [State]) {
+    states.sort_by(|a, b| match (a.score.is_nan(), b.score.is_nan()) {
+        (false, false) => a.score.partial_cmp(&b.score).expect("wtf"),
+        (a_nan, b_nan) => a_nan.cmp(&b_nan),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shape::Shape;
+
+    #[test]
+    fn nan_energies_sort_last() {
+        let scores = [0.5, f32::NAN, 0.25, -f32::NAN, 1.0];
+        let mut states: Vec<State> = scores.iter()
+            .map(|&score| State { shape: Shape::Ellipse { x: 0.0, y: 0.0, rx: 1.0, ry: 1.0 }, alpha: 128, score })
+            .collect();
+        sort_by_energy(&mut states);
+        let sorted: Vec<f32> = states.iter().map(|s| s.score).collect();
+        assert_eq!(&sorted[..3], &[0.25, 0.5, 1.0]);
+        assert!(sorted[3].is_nan() && sorted[4].is_nan());
+    }
+}
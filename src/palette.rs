@@ -0,0 +1,190 @@
+use rand::{Rng, StdRng};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use core::{Color, Pixels};
+
+#[derive(Debug, Clone)]
+pub struct Palette {
+    colors: Vec<Color>,
+}
+
+impl Palette {
+    pub fn new(colors: Vec<Color>) -> Palette {
+        assert!(!colors.is_empty(), "palette must have at least one color");
+        Palette { colors }
+    }
+
+    // hex colors separated by commas and/or whitespace, e.g. "#ff0000,00ff00 0000ff"
+    pub fn parse(s: &str) -> Result<Palette, String> {
+        let mut colors = vec![];
+        for token in s.split(|c: char| c == ',' || c.is_whitespace()) {
+            if token.is_empty() {
+                continue;
+            }
+            match Color::parse_hex(token) {
+                Some(color) => colors.push(color),
+                None => return Err(format!("invalid color: {}", token)),
+            }
+        }
+        if colors.is_empty() {
+            return Err(String::from("palette is empty"));
+        }
+        Ok(Palette::new(colors))
+    }
+
+    // same format as parse, one or more colors per line.  lines starting with ';' are comments.
+    pub fn load(path: &str) -> io::Result<Palette> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        let lines: Vec<&str> = contents.lines()
+            .filter(|line| !line.trim_start().starts_with(';'))
+            .collect();
+        Palette::parse(&lines.join("\n")).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // k-means clustering of the pixels' colors, seeded with k-means++
+    pub fn extract(pixels: &Pixels, k: usize) -> Palette {
+        assert!(k > 0, "palette size must be positive");
+        let mut points = Vec::with_capacity(pixels.w * pixels.h);
+        for y in 0..pixels.h {
+            for x in 0..pixels.w {
+                let c = pixels.get_arr(x, y);
+                points.push([c[0] as f32, c[1] as f32, c[2] as f32]);
+            }
+        }
+        let mut rng = StdRng::new().expect("wtf");
+        let centers = kmeans(&points, k, &mut rng);
+        Palette::new(centers.iter()
+            .map(|c| Color::new(c[0].round() as u8, c[1].round() as u8, c[2].round() as u8, 255))
+            .collect())
+    }
+
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    // The error of a blended shape is quadratic in its color, so the palette entry closest to the
+    // unconstrained optimum is also the one with the lowest error.
+    pub fn nearest(&self, c: &Color) -> Color {
+        let mut best = &self.colors[0];
+        let mut best_d = i32::MAX;
+        for p in &self.colors {
+            let dr = p.r() as i32 - c.r() as i32;
+            let dg = p.g() as i32 - c.g() as i32;
+            let db = p.b() as i32 - c.b() as i32;
+            let d = dr * dr + dg * dg + db * db;
+            if d < best_d {
+                best = p;
+                best_d = d;
+            }
+        }
+        Color::new(best.r(), best.g(), best.b(), c.a())
+    }
+}
+
+type Point = [f32; 3];
+
+const KMEANS_MAX_ITERATIONS: usize = 32;
+
+fn kmeans(points: &[Point], k: usize, rng: &mut StdRng) -> Vec<Point> {
+    let mut centers = kmeans_plus_plus(points, k, rng);
+    let mut assignments = vec![usize::MAX; points.len()];
+    for _ in 0..KMEANS_MAX_ITERATIONS {
+        let mut changed = false;
+        for (i, p) in points.iter().enumerate() {
+            let (j, _) = nearest_center(&centers, p);
+            if j != assignments[i] {
+                assignments[i] = j;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![[0f64; 3]; centers.len()];
+        let mut counts = vec![0usize; centers.len()];
+        for (p, &j) in points.iter().zip(assignments.iter()) {
+            sums[j][0] += p[0] as f64;
+            sums[j][1] += p[1] as f64;
+            sums[j][2] += p[2] as f64;
+            counts[j] += 1;
+        }
+        for j in 0..centers.len() {
+            if counts[j] == 0 {
+                // re-seed empty clusters with the point that is currently worst served
+                let far = farthest_point(points, &centers);
+                centers[j] = points[far];
+            } else {
+                let n = counts[j] as f64;
+                centers[j] = [(sums[j][0] / n) as f32, (sums[j][1] / n) as f32, (sums[j][2] / n) as f32];
+            }
+        }
+    }
+    centers
+}
+
+fn kmeans_plus_plus(points: &[Point], k: usize, rng: &mut StdRng) -> Vec<Point> {
+    let mut centers = vec![points[rng.gen_range(0, points.len())]];
+    let mut dists: Vec<f32> = points.iter().map(|p| distance2(p, &centers[0])).collect();
+    while centers.len() < k {
+        let total: f64 = dists.iter().map(|&d| d as f64).sum();
+        if total <= 0.0 {
+            // fewer distinct colors than requested
+            break;
+        }
+        let mut r = rng.gen_range(0.0, total);
+        let mut next = points.len() - 1;
+        for (i, &d) in dists.iter().enumerate() {
+            r -= d as f64;
+            if r <= 0.0 {
+                next = i;
+                break;
+            }
+        }
+        let center = points[next];
+        for (p, d) in points.iter().zip(dists.iter_mut()) {
+            let nd = distance2(p, &center);
+            if nd < *d {
+                *d = nd;
+            }
+        }
+        centers.push(center);
+    }
+    centers
+}
+
+fn nearest_center(centers: &[Point], p: &Point) -> (usize, f32) {
+    let mut best = 0;
+    let mut best_d = distance2(p, &centers[0]);
+    for (j, c) in centers.iter().enumerate().skip(1) {
+        let d = distance2(p, c);
+        if d < best_d {
+            best = j;
+            best_d = d;
+        }
+    }
+    (best, best_d)
+}
+
+fn farthest_point(points: &[Point], centers: &[Point]) -> usize {
+    let mut best = 0;
+    let mut best_d = -1.0;
+    for (i, p) in points.iter().enumerate() {
+        let (_, d) = nearest_center(centers, p);
+        if d > best_d {
+            best = i;
+            best_d = d;
+        }
+    }
+    best
+}
+
+fn distance2(a: &Point, b: &Point) -> f32 {
+    let dr = a[0] - b[0];
+    let dg = a[1] - b[1];
+    let db = a[2] - b[2];
+    dr * dr + dg * dg + db * db
+}
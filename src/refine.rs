@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use core::{Color, Pixels};
+use scanline::Scanline;
+
+// A rendered image together with its squared error against the target, kept per row.  Changing a
+// shape in the middle of the stack only affects the rows it covers, so a change can be scored by
+// recompositing just those rows from the image below the shape and the shapes above it.
+pub struct Composite {
+    target: Arc<Pixels>,
+    pub image: Pixels,
+    pub total: i64,
+    row_errors: Vec<i64>,
+    buffer: Pixels,
+    buffer_errors: Vec<i64>,
+}
+
+impl Composite {
+    pub fn new(target: Arc<Pixels>, image: Pixels) -> Composite {
+        let row_errors: Vec<i64> = (0..image.h).map(|y| row_error(&target, &image, y)).collect();
+        let total = row_errors.iter().sum();
+        let buffer = image.clone();
+        let buffer_errors = vec![0; image.h];
+        Composite { target, image, total, row_errors, buffer, buffer_errors }
+    }
+
+    pub fn score(&self) -> f32 {
+        let ni = self.image.w * self.image.h * 4;
+        (self.total as f32 / ni as f32).sqrt() / 255.0
+    }
+
+    // Total error if rows y1..y2 (inclusive) were recomposited from below, the given shape (or
+    // nothing) and the layers above it.  The result is kept until the next call so it can be
+    // accepted.
+    pub fn try_rows(&mut self, below: &Pixels, y1: usize, y2: usize,
+                    shape: Option<(&[Scanline], &Color)>,
+                    above: &[Vec<Scanline>], above_colors: &[Color]) -> i64 {
+        let w = self.image.w;
+        let a = 4 * w * y1;
+        let b = 4 * w * (y2 + 1);
+        self.buffer.buf[a..b].copy_from_slice(&below.buf[a..b]);
+        if let Some((lines, color)) = shape {
+            self.buffer.draw_lines(color, lines);
+        }
+        for (lines, color) in above.iter().zip(above_colors.iter()) {
+            for line in lines {
+                if line.y >= y1 && line.y <= y2 {
+                    self.buffer.draw_lines(color, ::std::slice::from_ref(line));
+                }
+            }
+        }
+        let mut total = self.total;
+        for y in y1..y2 + 1 {
+            let e = row_error(&self.target, &self.buffer, y);
+            self.buffer_errors[y] = e;
+            total += e - self.row_errors[y];
+        }
+        total
+    }
+
+    // keeps the rows composited by the last try_rows
+    pub fn accept(&mut self, y1: usize, y2: usize) {
+        let w = self.image.w;
+        let a = 4 * w * y1;
+        let b = 4 * w * (y2 + 1);
+        self.image.buf[a..b].copy_from_slice(&self.buffer.buf[a..b]);
+        for y in y1..y2 + 1 {
+            self.total += self.buffer_errors[y] - self.row_errors[y];
+            self.row_errors[y] = self.buffer_errors[y];
+        }
+    }
+}
+
+// first and last rows covered by either set of lines
+pub fn row_span(a: &[Scanline], b: &[Scanline]) -> Option<(usize, usize)> {
+    let mut span: Option<(usize, usize)> = None;
+    for line in a.iter().chain(b.iter()) {
+        span = Some(match span {
+            Some((y1, y2)) => (y1.min(line.y), y2.max(line.y)),
+            None => (line.y, line.y),
+        });
+    }
+    span
+}
+
+fn row_error(target: &Pixels, image: &Pixels, y: usize) -> i64 {
+    let a = 4 * image.w * y;
+    let b = a + 4 * image.w;
+    let mut total = 0i64;
+    for (t, p) in target.buf[a..b].iter().zip(image.buf[a..b].iter()) {
+        let d = *t as i32 - *p as i32;
+        total += (d * d) as i64;
+    }
+    total
+}
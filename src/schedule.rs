@@ -0,0 +1,36 @@
+use shape::Constraints;
+
+#[derive(Debug, Copy, Clone)]
+pub enum Decay {
+    Linear,
+    Exponential,
+}
+
+// Coarse-to-fine search: the largest allowed shape size shrinks from start to end over the course of
+// a run, so early steps lay down big shapes and later ones add detail.  Sizes are fractions of the
+// larger image dimension, like Constraints.
+#[derive(Debug, Copy, Clone)]
+pub struct Schedule {
+    pub decay: Decay,
+    pub start: f32,
+    pub end: f32,
+}
+
+impl Schedule {
+    pub fn size(&self, i: u32, n: u32) -> f32 {
+        let t = if n > 1 { i as f32 / (n - 1) as f32 } else { 0.0 };
+        match self.decay {
+            Decay::Linear => self.start + (self.end - self.start) * t,
+            Decay::Exponential => self.start * (self.end / self.start).powf(t),
+        }
+    }
+
+    // constraints for step i of n, never looser than c
+    pub fn constraints(&self, c: &Constraints, i: u32, n: u32) -> Constraints {
+        let size = self.size(i, n);
+        let mut c = *c;
+        c.max_size = c.max_size.min(size).max(c.min_size);
+        c.initial_size = c.max_size / 2.0;
+        c
+    }
+}
@@ -0,0 +1,484 @@
+// A small HTTP server, for generating pictures from a web page or another program without going
+// through files.  POST an image file to / with options in the query string:
+//   shape, n, alpha, size, quality, prescreen: as the command line's shape, num-shapes, alpha,
+//     output-size, quality and prescreen
+//   format: sse (the default) for server-sent events, or json for one JSON object per line
+// e.g. curl --data-binary @in.jpg 'http://localhost:8080/?shape=ellipse&n=200&format=json'
+//
+// The response streams events as they happen, each a JSON object with a type:
+//   start: the working size (width, height) that shape coordinates are in, and the background
+//   shape: each shape as it's found, see json::shape_fields
+//   svg: the finished picture
+// Each connection runs on its own thread and serves one request.  At most MAX_CONNECTIONS are served
+// at once, and the rest are turned away with 503.  Of those, MAX_RUNS generate pictures at a time
+// while the others wait their turn, since each run already uses every CPU, and give up with 503
+// after QUEUE_TIMEOUT.
+
+use image;
+use num_cpus;
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use core::ColorMode;
+use json;
+use model::Model;
+use optimizer::OptimizerType;
+use shape::{Constraints, ShapeType};
+use util;
+
+const MAX_CONNECTIONS: usize = 32;
+const MAX_RUNS: usize = 2;
+const MAX_SHAPES: u32 = 5000;
+const MAX_UPLOAD: usize = 64 << 20;
+// longest request or header line, and most header lines
+const MAX_LINE: usize = 8 << 10;
+const MAX_HEADERS: usize = 100;
+// how long a client can go without sending, or without taking what's sent to it
+const TIMEOUT: Duration = Duration::from_secs(30);
+// how long a request waits for a run to finish before giving up, since a client that hangs up while
+// waiting isn't noticed until then
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(120);
+const STACK_SIZE: usize = 16 << 20;
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Options {
+    shape_type: ShapeType,
+    num_shapes: u32,
+    alpha: u8,
+    out_size: usize,
+    m: u8,
+    prescreen: usize,
+    sse: bool,
+}
+
+pub fn serve(addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("listening on http://{}", listener.local_addr()?);
+    let connections = Slots::new(MAX_CONNECTIONS);
+    let runs = Slots::new(MAX_RUNS);
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        if stream.set_read_timeout(Some(TIMEOUT)).is_err() || stream.set_write_timeout(Some(TIMEOUT)).is_err() {
+            continue;
+        }
+        let connection = match Slots::try_take(&connections) {
+            Some(slot) => slot,
+            None => {
+                let _ = respond(&mut stream, 503, "too many connections, try again later");
+                continue;
+            }
+        };
+        let runs = runs.clone();
+        // Pixels are built on the stack, more than fit in a new thread's default stack
+        let spawned = thread::Builder::new().stack_size(STACK_SIZE).spawn(move || {
+            // the client hanging up is the only way this fails, and there's no one left to tell
+            let _ = handle(stream, &runs);
+            drop(connection);
+        });
+        if let Err(e) = spawned {
+            eprintln!("couldn't start a thread for a connection: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle(mut out: TcpStream, runs: &Arc<Slots>) -> io::Result<()> {
+    let mut reader = BufReader::new(out.try_clone()?);
+    let request = match read_request(&mut reader, &mut out) {
+        Ok(request) => request,
+        Err((status, msg)) => return respond(&mut out, status, &msg),
+    };
+    if request.path != "/" {
+        return respond(&mut out, 404, "not found");
+    }
+    match request.method.as_ref() {
+        // CORS preflight, so pages served from elsewhere can POST
+        "OPTIONS" => {
+            write!(out, "HTTP/1.1 204 No Content\r\n\
+                         Access-Control-Allow-Origin: *\r\n\
+                         Access-Control-Allow-Methods: POST\r\n\
+                         Access-Control-Allow-Headers: Content-Type\r\n\
+                         Connection: close\r\n\r\n")
+        }
+        "POST" => {
+            let options = match options(&request.query) {
+                Ok(options) => options,
+                Err(msg) => return respond(&mut out, 400, &msg),
+            };
+            let img = match image::load_from_memory(&request.body) {
+                Ok(img) => img,
+                Err(e) => return respond(&mut out, 400, &format!("couldn't load image: {}", e)),
+            };
+            let _run = match Slots::take(runs, QUEUE_TIMEOUT) {
+                Some(slot) => slot,
+                None => return respond(&mut out, 503, "too busy, try again later"),
+            };
+            run(&mut out, img, &options)
+        }
+        _ => respond(&mut out, 405, "POST an image to /"),
+    }
+}
+
+fn run(out: &mut TcpStream, img: image::DynamicImage, options: &Options) -> io::Result<()> {
+    let content_type = if options.sse { "text/event-stream" } else { "application/x-ndjson" };
+    write!(out, "HTTP/1.1 200 OK\r\n\
+                 Content-Type: {}\r\n\
+                 Cache-Control: no-cache\r\n\
+                 Transfer-Encoding: chunked\r\n\
+                 Access-Control-Allow-Origin: *\r\n\
+                 Connection: close\r\n\r\n", content_type)?;
+
+    let cpus = num_cpus::get_physical();
+    let mut model = Model::new(img, cpus, options.out_size, None, ColorMode::Color, OptimizerType::HillClimb.build(),
+                               options.prescreen);
+    let start = format!("\"width\":{},\"height\":{},\"background\":\"{}\"",
+                        model.w, model.h, util::hex(&model.stream_header().bg));
+    send(out, options.sse, "start", &start)?;
+    for i in 0..options.num_shapes as usize {
+        model.step(options.shape_type, options.alpha, 1000, options.m, Constraints::default());
+        let (shape, color) = model.shape(i);
+        send(out, options.sse, "shape", &json::shape_fields(i, shape, &color, model.score()))?;
+    }
+    send(out, options.sse, "svg", &format!("\"svg\":\"{}\"", json::escape(&model.svg())))?;
+    out.write_all(b"0\r\n\r\n")
+}
+
+// sends a chunk with one event, whose JSON object is {"type":kind,fields}
+fn send(out: &mut TcpStream, sse: bool, kind: &str, fields: &str) -> io::Result<()> {
+    let object = format!("{{\"type\":\"{}\",{}}}", kind, fields);
+    let event = if sse {
+        format!("event: {}\ndata: {}\n\n", kind, object)
+    } else {
+        format!("{}\n", object)
+    };
+    out.write_all(format!("{:x}\r\n{}\r\n", event.len(), event).as_bytes())?;
+    out.flush()
+}
+
+fn respond(out: &mut TcpStream, status: u16, msg: &str) -> io::Result<()> {
+    let reason = match status {
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+    write!(out, "HTTP/1.1 {} {}\r\n\
+                 Content-Type: text/plain\r\n\
+                 Content-Length: {}\r\n\
+                 Access-Control-Allow-Origin: *\r\n\
+                 Connection: close\r\n\r\n{}\n", status, reason, msg.len() + 1, msg)
+}
+
+// out is where to say 100 Continue
+fn read_request<R: BufRead, W: Write>(reader: &mut R, out: &mut W) -> Result<Request, (u16, String)> {
+    let mut line = String::new();
+    read_line(reader, &mut line)?;
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err((400, String::from("bad request")));
+    }
+    let method = String::from(parts[0]);
+    let mut target = parts[1].splitn(2, '?');
+    let path = String::from(target.next().unwrap_or(""));
+    let query = parse_query(target.next().unwrap_or(""));
+
+    let mut content_length = None;
+    let mut expect_continue = false;
+    for i in 0.. {
+        if i == MAX_HEADERS {
+            return Err((431, String::from("too many headers")));
+        }
+        line.clear();
+        if read_line(reader, &mut line)? == 0 {
+            return Err((400, String::from("bad request")));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        if name == "content-length" {
+            content_length = Some(value.parse::<usize>().map_err(|_| (400, String::from("bad content length")))?);
+        } else if name == "expect" && value.eq_ignore_ascii_case("100-continue") {
+            expect_continue = true;
+        }
+    }
+
+    let mut body = vec![];
+    if method == "POST" {
+        let n = match content_length {
+            Some(n) if n > MAX_UPLOAD => return Err((413, format!("images are limited to {} bytes", MAX_UPLOAD))),
+            Some(n) => n,
+            None => return Err((411, String::from("content length required"))),
+        };
+        if expect_continue {
+            out.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").map_err(read_error)?;
+        }
+        body = vec![0; n];
+        reader.read_exact(&mut body).map_err(read_error)?;
+    }
+    Ok(Request { method, path, query, body })
+}
+
+// reads a line of at most MAX_LINE bytes into line, returning its length
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<usize, (u16, String)> {
+    let n = (&mut *reader).take(MAX_LINE as u64).read_line(line).map_err(read_error)?;
+    if n == MAX_LINE && !line.ends_with('\n') {
+        return Err((431, String::from("request line or header too long")));
+    }
+    Ok(n)
+}
+
+fn read_error(e: io::Error) -> (u16, String) {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => (408, String::from("timed out waiting for the request")),
+        _ => (400, String::from("bad request")),
+    }
+}
+
+fn options(query: &HashMap<String, String>) -> Result<Options, String> {
+    fn get<T: ::std::str::FromStr>(query: &HashMap<String, String>, key: &str, default: T) -> Result<T, String> {
+        match query.get(key) {
+            Some(v) => v.parse().map_err(|_| format!("invalid {}: {}", key, v)),
+            None => Ok(default),
+        }
+    }
+    let shape_type = match query.get("shape").map_or("triangle", |s| s.as_ref()) {
+        "triangle" => ShapeType::Triangle,
+        "ellipse" => ShapeType::Ellipse,
+        "rectangle" => ShapeType::Rectangle,
+        "rotated-rectangle" => ShapeType::RotatedRectangle,
+        s => return Err(format!("invalid shape: {}", s)),
+    };
+    let alpha = get(query, "alpha", 128u8)?;
+    if alpha == 0 {
+        return Err(String::from("alpha must be between 1-255"));
+    }
+    let out_size = get(query, "size", 1024usize)?;
+    if out_size == 0 {
+        return Err(String::from("size must be positive"));
+    }
+    let m = match get(query, "quality", 2u8)? {
+        1 => 1,
+        2 => 8,
+        3 => 16,
+        _ => return Err(String::from("quality must be between 1-3")),
+    };
+    let prescreen = get(query, "prescreen", 0usize)?;
+    let sse = match query.get("format").map_or("sse", |s| s.as_ref()) {
+        "sse" => true,
+        "json" => false,
+        s => return Err(format!("invalid format: {}", s)),
+    };
+    let num_shapes = get(query, "n", 100)?;
+    if num_shapes > MAX_SHAPES {
+        return Err(format!("n must be at most {}", MAX_SHAPES));
+    }
+    Ok(Options { shape_type, num_shapes, alpha, out_size, m, prescreen, sse })
+}
+
+// A fixed number of slots, for limiting how many of something happen at once
+struct Slots {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+// a slot that's taken until dropped
+struct Slot(Arc<Slots>);
+
+impl Slots {
+    fn new(n: usize) -> Arc<Slots> {
+        Arc::new(Slots { free: Mutex::new(n), freed: Condvar::new() })
+    }
+
+    fn try_take(slots: &Arc<Slots>) -> Option<Slot> {
+        let mut free = slots.free.lock().unwrap();
+        if *free == 0 {
+            return None;
+        }
+        *free -= 1;
+        Some(Slot(slots.clone()))
+    }
+
+    // waits up to timeout for a slot to be free
+    fn take(slots: &Arc<Slots>, timeout: Duration) -> Option<Slot> {
+        let deadline = Instant::now() + timeout;
+        let mut free = slots.free.lock().unwrap();
+        while *free == 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            free = slots.freed.wait_timeout(free, deadline - now).unwrap().0;
+        }
+        *free -= 1;
+        Some(Slot(slots.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+fn parse_query(s: &str) -> HashMap<String, String> {
+    s.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            (percent_decode(parts.next().unwrap_or("")), percent_decode(parts.next().unwrap_or("")))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let digit = |j: usize| bytes.get(j).and_then(|&b| (b as char).to_digit(16));
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], digit(i + 1), digit(i + 2)) {
+            (b'%', Some(hi), Some(lo)) => {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+            }
+            (b'+', _, _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // the request, or the error status
+    fn request(text: &str) -> Result<Request, u16> {
+        read_request(&mut Cursor::new(text.as_bytes().to_vec()), &mut vec![]).map_err(|e| e.0)
+    }
+
+    fn status(text: &str) -> u16 {
+        request(text).err().unwrap_or(200)
+    }
+
+    fn query(s: &str) -> Result<Options, String> {
+        options(&parse_query(s))
+    }
+
+    #[test]
+    fn reads_a_post() {
+        let mut out = vec![];
+        let text = "POST /?shape=ellipse&n=5 HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\nExpect: 100-continue\r\n\r\nabcdef";
+        let request = read_request(&mut Cursor::new(text.as_bytes().to_vec()), &mut out).unwrap_or_else(|e| panic!("{:?}", e));
+        assert_eq!((request.method.as_ref(), request.path.as_ref()), ("POST", "/"));
+        assert_eq!(request.query.get("shape").map(|s| s.as_ref()), Some("ellipse"));
+        assert_eq!(request.query.get("n").map(|s| s.as_ref()), Some("5"));
+        assert_eq!(request.body, b"abcd");
+        assert_eq!(out, b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        assert_eq!(status("GET / HTTP/1.1\r\n\r\n"), 200);
+        assert_eq!(status("POST / HTTP/1.1\r\n\r\n"), 411);
+        assert_eq!(status(&format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_UPLOAD + 1)), 413);
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n"), 400);
+        // the body is shorter than its length
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc"), 400);
+        assert_eq!(status("GET /\r\n\r\n"), 400);
+        // headers that never end
+        assert_eq!(status("GET / HTTP/1.1\r\nHost: x\r\n"), 400);
+    }
+
+    #[test]
+    fn limits_headers() {
+        let long = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(status(&long), 431);
+        let long = format!("GET /?{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(status(&long), 431);
+        let fits = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE - 5));
+        assert_eq!(status(&fits), 200);
+
+        let headers = |n: usize| (0..n).map(|i| format!("X{}: y\r\n", i)).collect::<String>();
+        assert_eq!(status(&format!("GET / HTTP/1.1\r\n{}\r\n", headers(MAX_HEADERS - 1))), 200);
+        assert_eq!(status(&format!("GET / HTTP/1.1\r\n{}\r\n", headers(MAX_HEADERS))), 431);
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b+c%2Bd"), "a b c+d");
+        assert_eq!(percent_decode("%zz"), "%zz");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%e2%9c%93"), "\u{2713}");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+    }
+
+    #[test]
+    fn checks_options() {
+        let defaults = query("").expect("wtf");
+        assert_eq!((defaults.num_shapes, defaults.alpha, defaults.m, defaults.prescreen), (100, 128, 8, 0));
+        assert!(defaults.sse);
+        assert!(query("shape=circle").is_err());
+        assert!(query("shape=rotated-rectangle").is_ok());
+        assert!(query(&format!("n={}", MAX_SHAPES)).is_ok());
+        assert!(query(&format!("n={}", MAX_SHAPES + 1)).is_err());
+        assert!(query("n=-1").is_err());
+        assert!(query("quality=3").is_ok());
+        assert!(query("quality=4").is_err());
+        assert!(query("alpha=0").is_err());
+        assert!(query("alpha=256").is_err());
+        assert!(query("size=0").is_err());
+        assert!(query("format=xml").is_err());
+    }
+
+    #[test]
+    fn slots_run_out_and_come_back() {
+        let slots = Slots::new(2);
+        let a = Slots::try_take(&slots).expect("wtf");
+        let _b = Slots::take(&slots, Duration::from_millis(10)).expect("wtf");
+        assert!(Slots::try_take(&slots).is_none());
+        assert!(Slots::take(&slots, Duration::from_millis(10)).is_none());
+
+        // a waiting take gets the slot as soon as it's given back
+        let waiting = {
+            let slots = slots.clone();
+            thread::spawn(move || Slots::take(&slots, Duration::from_secs(10)).is_some())
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(a);
+        assert!(waiting.join().expect("wtf"));
+        assert!(Slots::try_take(&slots).is_some());
+    }
+}
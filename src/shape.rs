@@ -5,8 +5,14 @@ use rand::{Rng, StdRng};
 use std::mem::swap;
 use std::cmp::{min, max};
 
+use std::f32;
+use std::f32::consts::PI;
+
 use scanline::Scanline;
-use util::{degrees, rng_normal, rotate, scale_dimen};
+use util::{degrees, rng_normal, rotate};
+
+const MAX_ATTEMPTS: usize = 100;
+const ELLIPSE_SEGMENTS: usize = 64;
 
 #[derive(Debug, Copy, Clone)]
 pub enum ShapeType {
@@ -16,35 +22,101 @@ pub enum ShapeType {
     RotatedRectangle,
 }
 
+// Coordinates are in working pixels but kept fractional, so shapes stay precise when scaled up for
+// output.  They are rounded to whole pixels only when rasterized.
 #[derive(Debug, Clone)]
 pub enum Shape {
-    Triangle { x1: i32, y1: i32, x2: i32, y2: i32, x3: i32, y3: i32 },
-    Ellipse { x: i32, y: i32, rx: i32, ry: i32 },
-    Rectangle { x1: i32, y1: i32, x2: i32, y2: i32 },
-    RotatedRectangle { x: i32, y: i32, sx: i32, sy: i32, angle: i32 },
+    Triangle { x1: f32, y1: f32, x2: f32, y2: f32, x3: f32, y3: f32 },
+    Ellipse { x: f32, y: f32, rx: f32, ry: f32 },
+    Rectangle { x1: f32, y1: f32, x2: f32, y2: f32 },
+    RotatedRectangle { x: f32, y: f32, sx: f32, sy: f32, angle: f32 },
+}
+
+// Limits on the size and proportions of shapes.  Sizes are fractions of the larger image dimension
+// and areas fractions of the image area, so the same limits work at any resolution.  New random
+// shapes are at most initial_size big; mutation can grow them up to max_size.
+#[derive(Debug, Copy, Clone)]
+pub struct Constraints {
+    pub initial_size: f32,
+    pub min_size: f32,
+    pub max_size: f32,
+    pub min_area: f32,
+    pub max_aspect: f32,
+}
+
+impl Default for Constraints {
+    fn default() -> Constraints {
+        Constraints {
+            initial_size: 0.125,
+            min_size: 0.0,
+            max_size: f32::INFINITY,
+            min_area: 0.0,
+            max_aspect: f32::INFINITY,
+        }
+    }
+}
+
+impl Constraints {
+    pub fn allows(&self, shape: &Shape, w: i32, h: i32) -> bool {
+        let size = max(w, h) as f32;
+        let (major, minor, area) = shape.extent();
+        minor >= self.min_size * size &&
+            major <= self.max_size * size &&
+            area >= self.min_area * (w * h) as f32 &&
+            major <= self.max_aspect * minor
+    }
+
+    // range of sizes in pixels for new random shapes
+    fn initial_extent(&self, w: i32, h: i32) -> (f32, f32) {
+        let size = max(w, h) as f32;
+        let lo = (self.min_size * size).max(1.0);
+        let hi = self.initial_size.min(self.max_size) * size;
+        (lo, lo.max(hi))
+    }
 }
 
 impl Shape {
-    pub fn random(t: ShapeType, w: usize, h: usize, rng: &mut StdRng) -> Shape {
+    pub fn random(t: ShapeType, w: usize, h: usize, c: &Constraints, rng: &mut StdRng) -> Shape {
         let w = w as i32;
         let h = h as i32;
-        match t {
-            ShapeType::Triangle => random_triangle(w, h, rng),
-            ShapeType::Ellipse => random_ellipse(w, h, rng),
-            ShapeType::Rectangle => random_rectangle(w, h, rng),
-            ShapeType::RotatedRectangle => random_rotated_rectangle(w, h, rng),
+        let (lo, hi) = c.initial_extent(w, h);
+        let mut shape;
+        let mut attempts = 0;
+        loop {
+            shape = match t {
+                ShapeType::Triangle => random_triangle(w, h, lo, hi, rng),
+                ShapeType::Ellipse => random_ellipse(w, h, lo, hi, rng),
+                ShapeType::Rectangle => random_rectangle(w, h, lo, hi, rng),
+                ShapeType::RotatedRectangle => random_rotated_rectangle(w, h, lo, hi, rng),
+            };
+            attempts += 1;
+            // give up rather than spin if the constraints can't be met at this size
+            if c.allows(&shape, w, h) || attempts >= MAX_ATTEMPTS {
+                return shape;
+            }
         }
     }
 
-    pub fn mutate(&mut self, w: usize, h: usize, rng: &mut StdRng) {
+    pub fn mutate(&mut self, w: usize, h: usize, c: &Constraints, rng: &mut StdRng) {
         let w = w as i32;
         let h = h as i32;
+        let original = self.clone();
+        for _ in 0..MAX_ATTEMPTS {
+            self.mutate_unconstrained(w, h, rng);
+            if c.allows(self, w, h) {
+                return;
+            }
+            *self = original.clone();
+        }
+    }
+
+    fn mutate_unconstrained(&mut self, w: i32, h: i32, rng: &mut StdRng) {
         match *self {
             Shape::Triangle {
                 ref mut x1, ref mut y1,
                 ref mut x2, ref mut y2,
                 ref mut x3, ref mut y3,
-            } => mutate_triangle(w, h, rng, x1, y1, x2, y2, x3, y3),
+            } => mutate_triangle(w, h, rng, [(x1, y1), (x2, y2), (x3, y3)]),
             Shape::Ellipse {
                 ref mut x, ref mut y,
                 ref mut rx, ref mut ry,
@@ -57,25 +129,167 @@ impl Shape {
                 ref mut x, ref mut y,
                 ref mut sx, ref mut sy,
                 ref mut angle,
-            } => mutate_rotated_rectangle(w, h, rng, x, y, sx, sy, angle),
+            } => mutate_rotated_rectangle(w, h, rng, (x, y), (sx, sy), angle),
+        }
+    }
+
+    // (longest dimension, shortest dimension, area).  for triangles the dimensions are the longest
+    // side and the height on it.
+    fn extent(&self) -> (f32, f32, f32) {
+        let area = self.area();
+        let (a, b) = match *self {
+            Shape::Triangle { x1, y1, x2, y2, x3, y3 } => {
+                let side = |ax: f32, ay: f32, bx: f32, by: f32| (bx - ax).hypot(by - ay);
+                let longest = side(x1, y1, x2, y2).max(side(x2, y2, x3, y3)).max(side(x3, y3, x1, y1));
+                let height = if longest > 0.0 { 2.0 * area / longest } else { 0.0 };
+                (longest, height)
+            }
+            Shape::Ellipse { rx, ry, .. } => (2.0 * rx, 2.0 * ry),
+            Shape::Rectangle { x1, y1, x2, y2 } => (x2 - x1 + 1.0, y2 - y1 + 1.0),
+            Shape::RotatedRectangle { sx, sy, .. } => (sx, sy),
+        };
+        (a.max(b), a.min(b), area)
+    }
+
+    // The geometry queries below work in working pixels, with pixel (i, j) centered on (i, j) as in
+    // rasterize and the svg output.  A shape covers the pixels whose centers it contains, so a
+    // rectangle's edges are half a pixel outside x1..x2 and y1..y2.
+
+    pub fn area(&self) -> f32 {
+        match *self {
+            Shape::Triangle { x1, y1, x2, y2, x3, y3 } => {
+                ((x2 - x1) * (y3 - y1) - (x3 - x1) * (y2 - y1)).abs() / 2.0
+            }
+            Shape::Ellipse { rx, ry, .. } => PI * rx * ry,
+            Shape::Rectangle { x1, y1, x2, y2 } => (x2 - x1 + 1.0) * (y2 - y1 + 1.0),
+            Shape::RotatedRectangle { sx, sy, .. } => sx * sy,
+        }
+    }
+
+    // (min x, min y, max x, max y)
+    pub fn bounding_box(&self) -> (f32, f32, f32, f32) {
+        match *self {
+            Shape::Ellipse { x, y, rx, ry } => (x - rx, y - ry, x + rx, y + ry),
+            Shape::Rectangle { x1, y1, x2, y2 } => (x1 - 0.5, y1 - 0.5, x2 + 0.5, y2 + 0.5),
+            _ => {
+                let points = self.polygon();
+                let mut bounds = (f32::INFINITY, f32::INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+                for &(x, y) in &points {
+                    bounds.0 = bounds.0.min(x);
+                    bounds.1 = bounds.1.min(y);
+                    bounds.2 = bounds.2.max(x);
+                    bounds.3 = bounds.3.max(y);
+                }
+                bounds
+            }
         }
     }
 
-    pub fn rasterize<'a>(&self, w: usize, h: usize, buf: &'a mut Vec<Scanline>) -> &'a [Scanline] {
+    pub fn contains(&self, px: f32, py: f32) -> bool {
+        match *self {
+            Shape::Triangle { x1, y1, x2, y2, x3, y3 } => {
+                let d1 = cross(x1, y1, x2, y2, px, py);
+                let d2 = cross(x2, y2, x3, y3, px, py);
+                let d3 = cross(x3, y3, x1, y1, px, py);
+                let negative = d1 < 0.0 || d2 < 0.0 || d3 < 0.0;
+                let positive = d1 > 0.0 || d2 > 0.0 || d3 > 0.0;
+                !(negative && positive)
+            }
+            Shape::Ellipse { x, y, rx, ry } => {
+                let dx = (px - x) / rx;
+                let dy = (py - y) / ry;
+                dx * dx + dy * dy <= 1.0
+            }
+            // ties go the way rasterize rounds
+            Shape::Rectangle { x1, y1, x2, y2 } => {
+                px > x1 - 0.5 && px <= x2 + 0.5 && py > y1 - 0.5 && py <= y2 + 0.5
+            }
+            Shape::RotatedRectangle { x, y, sx, sy, angle } => {
+                let (u, v) = rotate(px - x, py - y, -angle.to_radians());
+                u.abs() <= sx / 2.0 && v.abs() <= sy / 2.0
+            }
+        }
+    }
+
+    // Outline as a list of vertices in drawing order.  Ellipses are flattened to a polygon.
+    pub fn polygon(&self) -> Vec<(f32, f32)> {
+        match *self {
+            Shape::Triangle { x1, y1, x2, y2, x3, y3 } => vec![(x1, y1), (x2, y2), (x3, y3)],
+            Shape::Ellipse { x, y, rx, ry } => {
+                (0..ELLIPSE_SEGMENTS).map(|i| {
+                    let theta = 2.0 * PI * i as f32 / ELLIPSE_SEGMENTS as f32;
+                    (x + rx * theta.cos(), y + ry * theta.sin())
+                }).collect()
+            }
+            Shape::Rectangle { x1, y1, x2, y2 } => {
+                let (x1, y1, x2, y2) = (x1 - 0.5, y1 - 0.5, x2 + 0.5, y2 + 0.5);
+                vec![(x1, y1), (x2, y1), (x2, y2), (x1, y2)]
+            }
+            Shape::RotatedRectangle { x, y, sx, sy, angle } => rotated_corners(x, y, sx, sy, angle).to_vec(),
+        }
+    }
+
+    // Uniform crossover: each point or dimension comes from either parent.  Shapes of different types
+    // can't be combined, and a child that would be an invalid triangle is replaced by the first parent.
+    pub fn crossover(&self, other: &Shape, rng: &mut StdRng) -> Shape {
+        match (self, other) {
+            (&Shape::Triangle { x1, y1, x2, y2, x3, y3 },
+             &Shape::Triangle { x1: ox1, y1: oy1, x2: ox2, y2: oy2, x3: ox3, y3: oy3 }) => {
+                let (x1, y1) = if rng.gen() { (x1, y1) } else { (ox1, oy1) };
+                let (x2, y2) = if rng.gen() { (x2, y2) } else { (ox2, oy2) };
+                let (x3, y3) = if rng.gen() { (x3, y3) } else { (ox3, oy3) };
+                if is_valid_triangle(&x1, &y1, &x2, &y2, &x3, &y3) {
+                    Shape::Triangle { x1, y1, x2, y2, x3, y3 }
+                } else {
+                    self.clone()
+                }
+            }
+            (&Shape::Ellipse { x, y, rx, ry },
+             &Shape::Ellipse { x: ox, y: oy, rx: orx, ry: ory }) => {
+                let (x, y) = if rng.gen() { (x, y) } else { (ox, oy) };
+                let rx = if rng.gen() { rx } else { orx };
+                let ry = if rng.gen() { ry } else { ory };
+                Shape::Ellipse { x, y, rx, ry }
+            }
+            (&Shape::Rectangle { x1, y1, x2, y2 },
+             &Shape::Rectangle { x1: ox1, y1: oy1, x2: ox2, y2: oy2 }) => {
+                let (mut x1, mut y1) = if rng.gen() { (x1, y1) } else { (ox1, oy1) };
+                let (mut x2, mut y2) = if rng.gen() { (x2, y2) } else { (ox2, oy2) };
+                if x1 > x2 {
+                    swap(&mut x1, &mut x2);
+                }
+                if y1 > y2 {
+                    swap(&mut y1, &mut y2);
+                }
+                Shape::Rectangle { x1, y1, x2, y2 }
+            }
+            (&Shape::RotatedRectangle { x, y, sx, sy, angle },
+             &Shape::RotatedRectangle { x: ox, y: oy, sx: osx, sy: osy, angle: oangle }) => {
+                let (x, y) = if rng.gen() { (x, y) } else { (ox, oy) };
+                let sx = if rng.gen() { sx } else { osx };
+                let sy = if rng.gen() { sy } else { osy };
+                let angle = if rng.gen() { angle } else { oangle };
+                Shape::RotatedRectangle { x, y, sx, sy, angle }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    pub fn rasterize<'a>(&self, w: usize, h: usize, buf: &'a mut [Scanline]) -> &'a [Scanline] {
         let w = w as i32;
         let h = h as i32;
         match *self {
             Shape::Triangle { x1, y1, x2, y2, x3, y3 } => {
-                rasterize_triangle(w, h, x1, y1, x2, y2, x3, y3, buf)
+                rasterize_triangle(w, h, [(px(x1), px(y1)), (px(x2), px(y2)), (px(x3), px(y3))], buf)
             }
             Shape::Ellipse { x, y, rx, ry } => {
-                rasterize_ellipse(w, h, x, y, rx, ry, buf)
+                rasterize_ellipse(w, h, px(x), px(y), max(1, px(rx)), max(1, px(ry)), buf)
             }
             Shape::Rectangle { x1, y1, x2, y2 } => {
-                rasterize_rectangle(x1, y1, x2, y2, buf)
+                rasterize_rectangle(w, h, px(x1), px(y1), px(x2), px(y2), buf)
             }
             Shape::RotatedRectangle { x, y, sx, sy, angle } => {
-                rasterize_rotated_rectangle(w, h, x, y, sx, sy, angle, buf)
+                rasterize_rotated_rectangle(w, h, rotated_corners(x, y, sx, sy, angle), buf)
             }
         }
     }
@@ -91,10 +305,11 @@ impl Shape {
                         attrs, x, y, rx, ry)
             }
             Shape::Rectangle { x1, y1, x2, y2 } => {
-                let w = x2 - x1 + 1;
-                let h = y2 - y1 + 1;
+                // the whole pixels x1..x2, y1..y2
+                let w = x2 - x1 + 1.0;
+                let h = y2 - y1 + 1.0;
                 format!("<rect {} x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" />",
-                        attrs, x1, y1, w, h)
+                        attrs, x1 - 0.5, y1 - 0.5, w, h)
             }
             Shape::RotatedRectangle { x, y, sx, sy, angle } => {
                 format!("<g transform=\"translate({} {}) rotate({}) scale({} {})\"><rect {} x=\"-0.5\" y=\"-0.5\" width=\"1\" height=\"1\" /></g>",
@@ -107,56 +322,55 @@ impl Shape {
         match *self {
             Shape::Triangle { x1, y1, x2, y2, x3, y3 } => {
                 Shape::Triangle {
-                    x1: scale_dimen(x1, scale),
-                    y1: scale_dimen(y1, scale),
-                    x2: scale_dimen(x2, scale),
-                    y2: scale_dimen(y2, scale),
-                    x3: scale_dimen(x3, scale),
-                    y3: scale_dimen(y3, scale),
+                    x1: x1 * scale,
+                    y1: y1 * scale,
+                    x2: x2 * scale,
+                    y2: y2 * scale,
+                    x3: x3 * scale,
+                    y3: y3 * scale,
                 }
             }
             Shape::Ellipse { x, y, rx, ry } => {
                 Shape::Ellipse {
-                    x: scale_dimen(x, scale),
-                    y: scale_dimen(y, scale),
-                    rx: scale_dimen(rx, scale),
-                    ry: scale_dimen(ry, scale),
+                    x: x * scale,
+                    y: y * scale,
+                    rx: rx * scale,
+                    ry: ry * scale,
                 }
             }
             Shape::Rectangle { x1, y1, x2, y2 } => {
                 Shape::Rectangle {
-                    x1: scale_dimen(x1, scale),
-                    y1: scale_dimen(y1, scale),
-                    x2: scale_dimen(x2, scale),
-                    y2: scale_dimen(y2, scale),
+                    x1: x1 * scale,
+                    y1: y1 * scale,
+                    x2: x2 * scale,
+                    y2: y2 * scale,
                 }
             }
             Shape::RotatedRectangle { x, y, sx, sy, angle } => {
                 Shape::RotatedRectangle {
-                    x: scale_dimen(x, scale),
-                    y: scale_dimen(y, scale),
-                    sx: scale_dimen(sx, scale),
-                    sy: scale_dimen(sy, scale),
+                    x: x * scale,
+                    y: y * scale,
+                    sx: sx * scale,
+                    sy: sy * scale,
                     angle,
                 }
             }
         }
     }
 
-    #[cfg(target_os = "android")]
-    pub fn serialize(&self) -> String {
+    pub fn translated(&self, dx: f32, dy: f32) -> Shape {
         match *self {
             Shape::Triangle { x1, y1, x2, y2, x3, y3 } => {
-                format!("0:{},{},{},{},{},{}", x1, y1, x2, y2, x3, y3)
+                Shape::Triangle { x1: x1 + dx, y1: y1 + dy, x2: x2 + dx, y2: y2 + dy, x3: x3 + dx, y3: y3 + dy }
             }
             Shape::Ellipse { x, y, rx, ry } => {
-                format!("1:{},{},{},{}", x, y, rx, ry)
+                Shape::Ellipse { x: x + dx, y: y + dy, rx, ry }
             }
             Shape::Rectangle { x1, y1, x2, y2 } => {
-                format!("2:{},{},{},{}", x1, y1, x2, y2)
+                Shape::Rectangle { x1: x1 + dx, y1: y1 + dy, x2: x2 + dx, y2: y2 + dy }
             }
             Shape::RotatedRectangle { x, y, sx, sy, angle } => {
-                format!("3:{},{},{},{},{}", x, y, sx, sy, angle)
+                Shape::RotatedRectangle { x: x + dx, y: y + dy, sx, sy, angle }
             }
         }
     }
@@ -177,84 +391,83 @@ impl Shape {
 //    }
 }
 
-fn random_triangle(w: i32, h: i32, rng: &mut StdRng) -> Shape {
-    let mut x1 = rng.gen_range(0, w);
-    let mut y1 = rng.gen_range(0, h);
-    let mut x2 = x1 + rng.gen_range(0, 31) - 15;
-    let mut y2 = y1 + rng.gen_range(0, 31) - 15;
-    let mut x3 = x1 + rng.gen_range(0, 31) - 15;
-    let mut y3 = y1 + rng.gen_range(0, 31) - 15;
-    mutate_triangle(w, h, rng, &mut x1, &mut y1, &mut x2, &mut y2, &mut x3, &mut y3);
+// z component of (b - a) x (p - a)
+fn cross(ax: f32, ay: f32, bx: f32, by: f32, px: f32, py: f32) -> f32 {
+    (bx - ax) * (py - ay) - (by - ay) * (px - ax)
+}
+
+// nearest whole pixel
+fn px(a: f32) -> i32 {
+    a.round() as i32
+}
+
+fn random_triangle(w: i32, h: i32, lo: f32, hi: f32, rng: &mut StdRng) -> Shape {
+    let r = lo.max(hi / 2.0);
+    let mut x1 = rng.gen_range(0.0, w as f32);
+    let mut y1 = rng.gen_range(0.0, h as f32);
+    let mut x2 = x1 + rng.gen_range(-r, r + 1.0);
+    let mut y2 = y1 + rng.gen_range(-r, r + 1.0);
+    let mut x3 = x1 + rng.gen_range(-r, r + 1.0);
+    let mut y3 = y1 + rng.gen_range(-r, r + 1.0);
+    mutate_triangle(w, h, rng, [(&mut x1, &mut y1), (&mut x2, &mut y2), (&mut x3, &mut y3)]);
     Shape::Triangle { x1, y1, x2, y2, x3, y3 }
 }
 
-fn random_ellipse(w: i32, h: i32, rng: &mut StdRng) -> Shape {
-    let x = rng.gen_range(0, w);
-    let y = rng.gen_range(0, h);
-    let rx = rng.gen_range(0, 32) + 1;
-    let ry = rng.gen_range(0, 32) + 1;
+fn random_ellipse(w: i32, h: i32, lo: f32, hi: f32, rng: &mut StdRng) -> Shape {
+    let x = rng.gen_range(0.0, w as f32);
+    let y = rng.gen_range(0.0, h as f32);
+    let rx = rng.gen_range((lo / 2.0).max(1.0), hi + 1.0);
+    let ry = rng.gen_range((lo / 2.0).max(1.0), hi + 1.0);
     Shape::Ellipse { x, y, rx, ry }
 }
 
-fn random_rectangle(w: i32, h: i32, rng: &mut StdRng) -> Shape {
-    let x1 = rng.gen_range(0, w);
-    let y1 = rng.gen_range(0, h);
-    let x2 = clamp(x1 + rng.gen_range(0, 32) + 1, 0, w - 1);
-    let y2 = clamp(y1 + rng.gen_range(0, 32) + 1, 0, h - 1);
+fn random_rectangle(w: i32, h: i32, lo: f32, hi: f32, rng: &mut StdRng) -> Shape {
+    // an empty range panics, so sample the whole image even when it's a pixel wide
+    let x1 = clamp(rng.gen_range(0.0, w as f32), 0.0, (w - 1) as f32);
+    let y1 = clamp(rng.gen_range(0.0, h as f32), 0.0, (h - 1) as f32);
+    let x2 = clamp(x1 + rng.gen_range(lo, hi + 1.0), 0.0, (w - 1) as f32);
+    let y2 = clamp(y1 + rng.gen_range(lo, hi + 1.0), 0.0, (h - 1) as f32);
     Shape::Rectangle { x1, y1, x2, y2 }
 }
 
-fn random_rotated_rectangle(w: i32, h: i32, rng: &mut StdRng) -> Shape {
-    let x = rng.gen_range(0, w);
-    let y = rng.gen_range(0, h);
-    let sx = rng.gen_range(0, 32) + 1;
-    let sy = rng.gen_range(0, 32) + 1;
-    let angle = rng.gen_range(0, 360);
+fn random_rotated_rectangle(w: i32, h: i32, lo: f32, hi: f32, rng: &mut StdRng) -> Shape {
+    let x = rng.gen_range(0.0, w as f32);
+    let y = rng.gen_range(0.0, h as f32);
+    let sx = rng.gen_range(lo, hi + 1.0);
+    let sy = rng.gen_range(lo, hi + 1.0);
+    let angle = rng.gen_range(0.0, 360.0);
     Shape::RotatedRectangle { x, y, sx, sy, angle }
 }
 
-fn mutate_triangle(w: i32, h: i32, rng: &mut StdRng,
-                   x1: &mut i32, y1: &mut i32,
-                   x2: &mut i32, y2: &mut i32,
-                   x3: &mut i32, y3: &mut i32) {
-    let m: i32 = 16;
-    let min: i32 = -m;
-    let max_x: i32 = w - 1 + m;
-    let max_y: i32 = h - 1 + m;
+fn mutate_triangle(w: i32, h: i32, rng: &mut StdRng, mut points: [(&mut f32, &mut f32); 3]) {
+    let m = 16.0;
+    let min = -m;
+    let max_x = (w - 1) as f32 + m;
+    let max_y = (h - 1) as f32 + m;
     loop {
-        let dx = (rng_normal(rng) * 31.0) as i32;
-        let dy = (rng_normal(rng) * 31.0) as i32;
-        match rng.gen_range(0, 3) {
-            0 => {
-                *x1 = clamp(*x1 + dx, min, max_x);
-                *y1 = clamp(*y1 + dy, min, max_y);
-            }
-            1 => {
-                *x2 = clamp(*x2 + dx, min, max_x);
-                *y2 = clamp(*y2 + dy, min, max_y);
-            }
-            2 => {
-                *x3 = clamp(*x3 + dx, min, max_x);
-                *y3 = clamp(*y3 + dy, min, max_y);
-            }
-            _ => panic!("impossible")
+        let dx = rng_normal(rng) * 31.0;
+        let dy = rng_normal(rng) * 31.0;
+        {
+            let (ref mut x, ref mut y) = points[rng.gen_range(0, 3)];
+            **x = clamp(**x + dx, min, max_x);
+            **y = clamp(**y + dy, min, max_y);
         }
+        let [(ref x1, ref y1), (ref x2, ref y2), (ref x3, ref y3)] = points;
         if is_valid_triangle(x1, y1, x2, y2, x3, y3) {
             break;
         }
     }
 }
 
-fn is_valid_triangle(tx1: &i32, ty1: &i32, tx2: &i32, ty2: &i32, tx3: &i32, ty3: &i32) -> bool {
+fn is_valid_triangle(tx1: &f32, ty1: &f32, tx2: &f32, ty2: &f32, tx3: &f32, ty3: &f32) -> bool {
     const MIN_DEGREES: f32 = 15.0;
     let a1: f32;
     let a2: f32;
-    let a3: f32;
     {
-        let mut x1 = (*tx2 - *tx1) as f32;
-        let mut y1 = (*ty2 - *ty1) as f32;
-        let mut x2 = (*tx3 - *tx1) as f32;
-        let mut y2 = (*ty3 - *ty1) as f32;
+        let mut x1 = *tx2 - *tx1;
+        let mut y1 = *ty2 - *ty1;
+        let mut x2 = *tx3 - *tx1;
+        let mut y2 = *ty3 - *ty1;
         let d1 = (x1 * x1 + y1 * y1).sqrt();
         let d2 = (x2 * x2 + y2 * y2).sqrt();
         x1 /= d1;
@@ -264,10 +477,10 @@ fn is_valid_triangle(tx1: &i32, ty1: &i32, tx2: &i32, ty2: &i32, tx3: &i32, ty3:
         a1 = degrees((x1 * x2 + y1 * y2).acos());
     }
     {
-        let mut x1 = (*tx1 - *tx2) as f32;
-        let mut y1 = (*ty1 - *ty2) as f32;
-        let mut x2 = (*tx3 - *tx2) as f32;
-        let mut y2 = (*ty3 - *ty2) as f32;
+        let mut x1 = *tx1 - *tx2;
+        let mut y1 = *ty1 - *ty2;
+        let mut x2 = *tx3 - *tx2;
+        let mut y2 = *ty3 - *ty2;
         let d1 = (x1 * x1 + y1 * y1).sqrt();
         let d2 = (x2 * x2 + y2 * y2).sqrt();
         x1 /= d1;
@@ -276,38 +489,38 @@ fn is_valid_triangle(tx1: &i32, ty1: &i32, tx2: &i32, ty2: &i32, tx3: &i32, ty3:
         y2 /= d2;
         a2 = degrees((x1 * x2 + y1 * y2).acos());
     }
-    a3 = 180.0 - a1 - a2;
+    let a3 = 180.0 - a1 - a2;
     a1 > MIN_DEGREES && a2 > MIN_DEGREES && a3 > MIN_DEGREES
 }
 
 fn mutate_ellipse(w: i32, h: i32, rng: &mut StdRng,
-                  x: &mut i32, y: &mut i32,
-                  rx: &mut i32, ry: &mut i32) {
+                  x: &mut f32, y: &mut f32,
+                  rx: &mut f32, ry: &mut f32) {
     match rng.gen_range(0, 3) {
         0 => {
-            *x = clamp(*x + (rng_normal(rng) * 16.0) as i32, 0, w - 1);
-            *y = clamp(*y + (rng_normal(rng) * 16.0) as i32, 0, h - 1);
+            *x = clamp(*x + rng_normal(rng) * 16.0, 0.0, (w - 1) as f32);
+            *y = clamp(*y + rng_normal(rng) * 16.0, 0.0, (h - 1) as f32);
         }
         1 => {
-            *rx = clamp(*rx + (rng_normal(rng) * 16.0) as i32, 0, w - 1);
+            *rx = clamp(*rx + rng_normal(rng) * 16.0, 1.0, (w - 1) as f32);
         }
         _ => {
-            *ry = clamp(*ry + (rng_normal(rng) * 16.0) as i32, 0, h - 1);
+            *ry = clamp(*ry + rng_normal(rng) * 16.0, 1.0, (h - 1) as f32);
         }
     }
 }
 
 fn mutate_rectangle(w: i32, h: i32, rng: &mut StdRng,
-                    x1: &mut i32, y1: &mut i32,
-                    x2: &mut i32, y2: &mut i32) {
+                    x1: &mut f32, y1: &mut f32,
+                    x2: &mut f32, y2: &mut f32) {
     match rng.gen_range(0, 2) {
         0 => {
-            *x1 = clamp(*x1 + (rng_normal(rng) * 16.0) as i32, 0, w - 1);
-            *y1 = clamp(*y1 + (rng_normal(rng) * 16.0) as i32, 0, h - 1);
+            *x1 = clamp(*x1 + rng_normal(rng) * 16.0, 0.0, (w - 1) as f32);
+            *y1 = clamp(*y1 + rng_normal(rng) * 16.0, 0.0, (h - 1) as f32);
         }
         _ => {
-            *x2 = clamp(*x1 + (rng_normal(rng) * 16.0) as i32, 0, w - 1);
-            *y2 = clamp(*y1 + (rng_normal(rng) * 16.0) as i32, 0, h - 1);
+            *x2 = clamp(*x2 + rng_normal(rng) * 16.0, 0.0, (w - 1) as f32);
+            *y2 = clamp(*y2 + rng_normal(rng) * 16.0, 0.0, (h - 1) as f32);
         }
     }
     if *x1 > *x2 {
@@ -319,29 +532,26 @@ fn mutate_rectangle(w: i32, h: i32, rng: &mut StdRng,
 }
 
 fn mutate_rotated_rectangle(w: i32, h: i32, rng: &mut StdRng,
-                            x: &mut i32, y: &mut i32,
-                            sx: &mut i32, sy: &mut i32,
-                            angle: &mut i32) {
+                            (x, y): (&mut f32, &mut f32),
+                            (sx, sy): (&mut f32, &mut f32),
+                            angle: &mut f32) {
     match rng.gen_range(0, 3) {
         0 => {
-            *x = clamp(*x + (rng_normal(rng) * 16.0) as i32, 0, w - 1);
-            *y = clamp(*y + (rng_normal(rng) * 16.0) as i32, 0, h - 1);
+            *x = clamp(*x + rng_normal(rng) * 16.0, 0.0, (w - 1) as f32);
+            *y = clamp(*y + rng_normal(rng) * 16.0, 0.0, (h - 1) as f32);
         }
         1 => {
-            *sx = clamp(*sx + (rng_normal(rng) * 16.0) as i32, 1, w - 1);
-            *sy = clamp(*sy + (rng_normal(rng) * 16.0) as i32, 1, h - 1);
+            *sx = clamp(*sx + rng_normal(rng) * 16.0, 1.0, (w - 1) as f32);
+            *sy = clamp(*sy + rng_normal(rng) * 16.0, 1.0, (h - 1) as f32);
         }
         _ => {
-            *angle = (*angle + (rng_normal(rng) * 32.0) as i32) % 360;
+            *angle = (*angle + rng_normal(rng) * 32.0) % 360.0;
         }
     }
 }
 
-fn rasterize_triangle<'a>(w: i32, h: i32,
-                          mut x1: i32, mut y1: i32,
-                          mut x2: i32, mut y2: i32,
-                          mut x3: i32, mut y3: i32,
-                          mut buf: &'a mut Vec<Scanline>) -> &'a [Scanline] {
+fn rasterize_triangle(w: i32, h: i32, points: [(i32, i32); 3], buf: &mut [Scanline]) -> &[Scanline] {
+    let [(mut x1, mut y1), (mut x2, mut y2), (mut x3, mut y3)] = points;
     if y1 > y3 {
         swap(&mut x1, &mut x3);
         swap(&mut y1, &mut y3);
@@ -355,25 +565,25 @@ fn rasterize_triangle<'a>(w: i32, h: i32,
         swap(&mut y2, &mut y3);
     }
     if y2 == y3 {
-        let count = rasterize_triangle_bottom(w, h, x1, y1, x2, y2, x3, y3, &mut buf, 0);
+        let count = rasterize_triangle_bottom(w, h, (x1, y1), (x2, y2), (x3, y3), buf, 0);
         &buf[0..count]
     } else if y1 == y2 {
-        let count = rasterize_triangle_top(w, h, x1, y1, x2, y2, x3, y3, &mut buf, 0);
+        let count = rasterize_triangle_top(w, h, (x1, y1), (x2, y2), (x3, y3), buf, 0);
         &buf[0..count]
     } else {
         let x4 = x1 + (((y2 - y1) as f32 / (y3 - y1) as f32) * (x3 - x1) as f32) as i32;
         let y4 = y2;
-        let first = rasterize_triangle_bottom(w, h, x1, y1, x2, y2, x4, y4, &mut buf, 0);
-        let last = rasterize_triangle_top(w, h, x2, y2, x4, y4, x3, y3, &mut buf, first);
+        let first = rasterize_triangle_bottom(w, h, (x1, y1), (x2, y2), (x4, y4), buf, 0);
+        let last = rasterize_triangle_top(w, h, (x2, y2), (x4, y4), (x3, y3), buf, first);
         &buf[0..first + last]
     }
 }
 
 fn rasterize_triangle_bottom(w: i32, h: i32,
-                             x1: i32, y1: i32,
-                             x2: i32, y2: i32,
-                             x3: i32, y3: i32,
-                             buf: &mut Vec<Scanline>,
+                             (x1, y1): (i32, i32),
+                             (x2, y2): (i32, i32),
+                             (x3, y3): (i32, i32),
+                             buf: &mut [Scanline],
                              offset: usize) -> usize {
     let s1 = (x2 - x1) as f32 / (y2 - y1) as f32;
     let s2 = (x3 - x1) as f32 / (y3 - y1) as f32;
@@ -395,15 +605,15 @@ fn rasterize_triangle_bottom(w: i32, h: i32,
         }
         y += 1;
     }
-    return count;
+    count
 }
 
-fn rasterize_triangle_top<'a>(w: i32, h: i32,
-                              x1: i32, y1: i32,
-                              x2: i32, y2: i32,
-                              x3: i32, y3: i32,
-                              buf: &'a mut Vec<Scanline>,
-                              offset: usize) -> usize {
+fn rasterize_triangle_top(w: i32, h: i32,
+                          (x1, y1): (i32, i32),
+                          (x2, y2): (i32, i32),
+                          (x3, y3): (i32, i32),
+                          buf: &mut [Scanline],
+                          offset: usize) -> usize {
     let s1 = (x3 - x1) as f32 / (y3 - y1) as f32;
     let s2 = (x3 - x2) as f32 / (y3 - y2) as f32;
     let mut ax = x3 as f32;
@@ -424,13 +634,13 @@ fn rasterize_triangle_top<'a>(w: i32, h: i32,
         }
         y -= 1;
     }
-    return count;
+    count
 }
 
-fn rasterize_ellipse<'a>(w: i32, h: i32,
-                         x: i32, y: i32,
-                         rx: i32, ry: i32,
-                         buf: &'a mut Vec<Scanline>) -> &'a [Scanline] {
+fn rasterize_ellipse(w: i32, h: i32,
+                     x: i32, y: i32,
+                     rx: i32, ry: i32,
+                     buf: &mut [Scanline]) -> &[Scanline] {
     let aspect = rx as f32 / ry as f32;
     let mut count = 0;
     for dy in 0..ry {
@@ -466,42 +676,51 @@ fn rasterize_ellipse<'a>(w: i32, h: i32,
     &buf[0..count]
 }
 
-fn rasterize_rectangle<'a>(x1: i32, y1: i32,
-                           x2: i32, y2: i32,
-                           buf: &'a mut Vec<Scanline>) -> &'a [Scanline] {
+fn rasterize_rectangle(w: i32, h: i32,
+                       x1: i32, y1: i32,
+                       x2: i32, y2: i32,
+                       buf: &mut [Scanline]) -> &[Scanline] {
+    let (x1, x2) = (max(x1, 0), min(x2, w - 1));
+    let (y1, y2) = (max(y1, 0), min(y2, h - 1));
+    if x1 > x2 || y1 > y2 {
+        return &buf[0..0];
+    }
     for y in y1..y2 + 1 {
         let line = &mut buf[(y - y1) as usize];
         line.y = y as usize;
         line.x1 = x1 as usize;
         line.x2 = x2 as usize;
     }
-    &buf[0..(y2 - y1) as usize]
+    &buf[0..(y2 - y1 + 1) as usize]
 }
 
-fn rasterize_rotated_rectangle<'a>(w: i32, h: i32,
-                                   x: i32, y: i32,
-                                   sx: i32, sy: i32,
-                                   angle: i32,
-                                   buf: &'a mut Vec<Scanline>) -> &'a [Scanline] {
-    let sx = sx as f32;
-    let sy = sy as f32;
-    let angle = (angle as f32).to_radians();
-    let (rx1, ry1) = rotate(-sx / 2.0, -sy / 2.0, angle);
-    let (rx2, ry2) = rotate(sx / 2.0, -sy / 2.0, angle);
-    let (rx3, ry3) = rotate(sx / 2.0, sy / 2.0, angle);
-    let (rx4, ry4) = rotate(-sx / 2.0, sy / 2.0, angle);
-    let (x1, y1) = ((rx1 as i32) + x, (ry1 as i32) + y);
-    let (x2, y2) = ((rx2 as i32) + x, (ry2 as i32) + y);
-    let (x3, y3) = ((rx3 as i32) + x, (ry3 as i32) + y);
-    let (x4, y4) = ((rx4 as i32) + x, (ry4 as i32) + y);
-    let miny = min(y1, min(y2, min(y3, y4)));
-    let maxy = max(y1, max(y2, max(y3, y4)));
-    let n = maxy - miny + 1;
-    let mut mins = Vec::with_capacity(n as usize);
-    let mut maxs = Vec::with_capacity(n as usize);
-    for i in 0..n {
-        mins.push(w);
-        maxs.push(0);
+// corners in drawing order, starting from the one that's top left before rotating
+fn rotated_corners(x: f32, y: f32, sx: f32, sy: f32, angle: f32) -> [(f32, f32); 4] {
+    let angle = angle.to_radians();
+    let corner = |dx: f32, dy: f32| {
+        let (rx, ry) = rotate(dx / 2.0, dy / 2.0, angle);
+        (x + rx, y + ry)
+    };
+    [corner(-sx, -sy), corner(sx, -sy), corner(sx, sy), corner(-sx, sy)]
+}
+
+fn rasterize_rotated_rectangle(w: i32, h: i32, corners: [(f32, f32); 4], buf: &mut [Scanline]) -> &[Scanline] {
+    let (x1, y1) = (px(corners[0].0), px(corners[0].1));
+    let (x2, y2) = (px(corners[1].0), px(corners[1].1));
+    let (x3, y3) = (px(corners[2].0), px(corners[2].1));
+    let (x4, y4) = (px(corners[3].0), px(corners[3].1));
+    let miny = max(min(y1, min(y2, min(y3, y4))), 0);
+    let maxy = min(max(y1, max(y2, max(y3, y4))), h - 1);
+    if miny > maxy {
+        return &buf[0..0];
+    }
+    // Each row's extent is accumulated in place in buf[y], with x shifted by one so that points left
+    // of the image, clamped to -1, still fit.  An untouched row has x1 > x2.
+    for y in miny..maxy + 1 {
+        let line = &mut buf[y as usize];
+        line.y = y as usize;
+        line.x1 = (w + 1) as usize;
+        line.x2 = 0;
     }
     let xs = &[x1, x2, x3, x4, x1];
     let ys = &[y1, y2, y3, y4, y1];
@@ -511,27 +730,194 @@ fn rasterize_rotated_rectangle<'a>(w: i32, h: i32,
         let count = ((dx * dx + dy * dy).sqrt() as i32) * 2;
         for j in 0..count {
             let t = j as f32 / (count - 1) as f32;
-            let xi = (x + dx * t) as i32;
-            let yi = (y + dy * t) as i32 - miny;
-            mins[yi as usize] = min(mins[yi as usize], xi);
-            maxs[yi as usize] = max(maxs[yi as usize], xi);
+            let xi = (clamp((x + dx * t) as i32, -1, w) + 1) as usize;
+            let yi = (y + dy * t) as i32;
+            if yi < miny || yi > maxy {
+                continue;
+            }
+            let line = &mut buf[yi as usize];
+            line.x1 = min(line.x1, xi);
+            line.x2 = max(line.x2, xi);
         }
     }
+    // rows are compacted to the front of buf, which never overwrites a row not yet read
     let mut count = 0;
-    for i in 0..n {
-        let y = miny + i;
-        if y < 0 || y >= h {
+    for y in miny as usize..maxy as usize + 1 {
+        if buf[y].x2 == 0 {
             continue;
         }
-        let a = max(mins[i as usize], 0);
-        let b = min(maxs[i as usize], w - 1);
+        let a = max(buf[y].x1, 1) - 1;
+        let b = min(buf[y].x2, w as usize) - 1;
         if b >= a {
             let line = &mut buf[count];
-            line.y = y as usize;
-            line.x1 = a as usize;
-            line.x2 = b as usize;
+            line.y = y;
+            line.x1 = a;
+            line.x2 = b;
             count += 1;
         }
     }
-    &buf[0..count as usize]
+    &buf[0..count]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: usize = 100;
+    const H: usize = 100;
+
+    fn shapes() -> Vec<Shape> {
+        vec![
+            Shape::Triangle { x1: 20.0, y1: 15.0, x2: 80.0, y2: 30.0, x3: 40.0, y3: 85.0 },
+            Shape::Triangle { x1: 10.3, y1: 50.7, x2: 90.2, y2: 45.1, x3: 55.6, y3: 60.4 },
+            Shape::Ellipse { x: 50.0, y: 50.0, rx: 30.0, ry: 12.0 },
+            Shape::Ellipse { x: 40.4, y: 60.6, rx: 9.0, ry: 25.0 },
+            Shape::Rectangle { x1: 10.0, y1: 20.0, x2: 60.0, y2: 35.0 },
+            Shape::Rectangle { x1: 30.4, y1: 40.6, x2: 70.5, y2: 41.2 },
+            Shape::RotatedRectangle { x: 50.0, y: 50.0, sx: 40.0, sy: 20.0, angle: 30.0 },
+            Shape::RotatedRectangle { x: 45.5, y: 55.2, sx: 12.0, sy: 50.0, angle: 290.0 },
+        ]
+    }
+
+    // the pixels rasterize covers
+    fn mask(shape: &Shape) -> Vec<bool> {
+        let mut mask = vec![false; W * H];
+        let mut buf = Scanline::buffer(H);
+        for line in shape.rasterize(W, H, &mut buf) {
+            for x in line.x1..line.x2 + 1 {
+                mask[line.y * W + x] = true;
+            }
+        }
+        mask
+    }
+
+    // whether the shape's edge passes within r of (x, y)
+    fn near_edge(shape: &Shape, x: f32, y: f32, r: f32) -> bool {
+        let inside = shape.contains(x, y);
+        (0..64).any(|i| {
+            let theta = 2.0 * PI * i as f32 / 64.0;
+            (1..5).any(|j| {
+                let d = r * j as f32 / 4.0;
+                shape.contains(x + d * theta.cos(), y + d * theta.sin()) != inside
+            })
+        })
+    }
+
+    // the scanlines as (y, x1, x2)
+    fn lines(shape: &Shape) -> Vec<(usize, usize, usize)> {
+        let mut buf = Scanline::buffer(H);
+        shape.rasterize(W, H, &mut buf).iter().map(|l| (l.y, l.x1, l.x2)).collect()
+    }
+
+    #[test]
+    fn rasterize_rounds_to_nearest_pixel() {
+        // halves round away from zero
+        let rectangle = Shape::Rectangle { x1: 1.4, y1: 0.6, x2: 3.5, y2: 2.49 };
+        assert_eq!(lines(&rectangle), [(1, 1, 4), (2, 1, 4)]);
+        let ellipse = Shape::Ellipse { x: 10.5, y: 20.4, rx: 1.6, ry: 0.7 };
+        assert_eq!(lines(&ellipse), [(20, 9, 13)]);
+        let whole = Shape::Triangle { x1: 2.0, y1: 3.0, x2: 8.0, y2: 3.0, x3: 2.0, y3: 9.0 };
+        let fractional = Shape::Triangle { x1: 1.6, y1: 3.4, x2: 8.2, y2: 2.5, x3: 2.4, y3: 9.1 };
+        assert_eq!(lines(&fractional), lines(&whole));
+    }
+
+    #[test]
+    fn random_and_mutated_shapes_fit_one_pixel_wide_images() {
+        let mut rng = StdRng::new().expect("wtf");
+        let c = Constraints::default();
+        let types = [ShapeType::Triangle, ShapeType::Ellipse, ShapeType::Rectangle, ShapeType::RotatedRectangle];
+        for &(w, h) in &[(1, 300), (300, 1), (1, 1)] {
+            for t in &types {
+                for _ in 0..100 {
+                    let mut shape = Shape::random(*t, w, h, &c, &mut rng);
+                    shape.mutate(w, h, &c, &mut rng);
+                    if let Shape::Rectangle { x1, y1, x2, y2 } = shape {
+                        assert!(x1 >= 0.0 && x2 <= (w - 1) as f32 && y1 >= 0.0 && y2 <= (h - 1) as f32, "{:?}", shape);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mutating_a_rectangle_moves_a_corner_a_little() {
+        let mut rng = StdRng::new().expect("wtf");
+        let c = Constraints::default();
+        for _ in 0..200 {
+            let mut shape = Shape::Rectangle { x1: 10.0, y1: 10.0, x2: 900.0, y2: 900.0 };
+            shape.mutate(1000, 1000, &c, &mut rng);
+            // a corner moves around where it was, not to near the other corner
+            if let Shape::Rectangle { x1, y1, x2, y2 } = shape {
+                assert!(x1 < 500.0 && y1 < 500.0 && x2 > 500.0 && y2 > 500.0, "{:?}", shape);
+            }
+        }
+    }
+
+    #[test]
+    fn contains_matches_rasterize() {
+        for shape in shapes() {
+            let mask = mask(&shape);
+            for y in 0..H {
+                for x in 0..W {
+                    let (px, py) = (x as f32, y as f32);
+                    // the scanline rasterizers round edges to whole pixels, and triangles and
+                    // rotated rectangles truncate them
+                    if mask[y * W + x] != shape.contains(px, py) {
+                        assert!(near_edge(&shape, px, py, 1.5), "{:?} at ({}, {})", shape, x, y);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rectangle_contains_is_exact() {
+        let rectangles = [
+            Shape::Rectangle { x1: 10.0, y1: 20.0, x2: 60.0, y2: 35.0 },
+            Shape::Rectangle { x1: 30.4, y1: 40.6, x2: 70.5, y2: 41.2 },
+            Shape::Rectangle { x1: -5.0, y1: 90.5, x2: 12.5, y2: 120.0 },
+        ];
+        for shape in &rectangles {
+            let mask = mask(shape);
+            for y in 0..H {
+                for x in 0..W {
+                    assert_eq!(mask[y * W + x], shape.contains(x as f32, y as f32), "{:?} at ({}, {})", shape, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn area_matches_covered_pixels() {
+        for shape in shapes() {
+            let covered = mask(&shape).iter().filter(|&&m| m).count() as f32;
+            let (x1, y1, x2, y2) = shape.bounding_box();
+            // pixels along the edge can go either way
+            let tolerance = (x2 - x1 + y2 - y1) * 2.0;
+            assert!((covered - shape.area()).abs() <= tolerance,
+                    "{:?} covers {} pixels, area {}", shape, covered, shape.area());
+        }
+        let rectangle = Shape::Rectangle { x1: 10.0, y1: 20.0, x2: 60.0, y2: 35.0 };
+        assert_eq!(mask(&rectangle).iter().filter(|&&m| m).count() as f32, rectangle.area());
+    }
+
+    #[test]
+    fn polygon_and_bounds_agree_with_contains() {
+        for shape in shapes() {
+            let (x1, y1, x2, y2) = shape.bounding_box();
+            for &(x, y) in &shape.polygon() {
+                assert!(x >= x1 - 1e-3 && x <= x2 + 1e-3 && y >= y1 - 1e-3 && y <= y2 + 1e-3);
+            }
+            let mask = mask(&shape);
+            for y in 0..H {
+                for x in 0..W {
+                    if mask[y * W + x] {
+                        let (px, py) = (x as f32, y as f32);
+                        assert!(px >= x1 - 1.0 && px <= x2 + 1.0 && py >= y1 - 1.0 && py <= y2 + 1.0,
+                                "{:?} at ({}, {})", shape, x, y);
+                    }
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,275 @@
+// Inner loops of scoring and drawing, over a span of RGBA pixels.  On x86_64 CPUs with AVX2 the spans
+// are processed 4 pixels at a time; elsewhere, and for the pixels left over, the scalar versions run.
+// Both produce exactly the same results.
+
+use core::Color;
+use util;
+
+// Per channel (r, g, b) sums of target - current and of current, for compute_color.
+pub fn color_sums(target: &[u8], current: &[u8], diffs: &mut [i64; 3], sums: &mut [i64; 3]) {
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            done = unsafe { avx2::color_sums(target, current, diffs, sums) };
+        }
+    }
+    color_sums_scalar(&target[done..], &current[done..], diffs, sums);
+}
+
+// Change in squared error against target when the pixels in before are replaced by those in after.
+pub fn error_delta(target: &[u8], before: &[u8], after: &[u8]) -> i64 {
+    let mut total = 0;
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            let (n, t) = unsafe { avx2::error_delta(target, before, after) };
+            done = n;
+            total = t;
+        }
+    }
+    total + error_delta_scalar(&target[done..], &before[done..], &after[done..])
+}
+
+// Blends c over every pixel of buf.
+pub fn blend_span(buf: &mut [u8], c: &Color) {
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            done = unsafe { avx2::blend_span(buf, c) };
+        }
+    }
+    blend_span_scalar(&mut buf[done..], c);
+}
+
+fn color_sums_scalar(target: &[u8], current: &[u8], diffs: &mut [i64; 3], sums: &mut [i64; 3]) {
+    for (t, c) in target.chunks(4).zip(current.chunks(4)) {
+        for k in 0..3 {
+            diffs[k] += t[k] as i64 - c[k] as i64;
+            sums[k] += c[k] as i64;
+        }
+    }
+}
+
+fn error_delta_scalar(target: &[u8], before: &[u8], after: &[u8]) -> i64 {
+    let mut total = 0i64;
+    for ((t, b), a) in target.iter().zip(before.iter()).zip(after.iter()) {
+        let d1 = *t as i32 - *b as i32;
+        let d2 = *t as i32 - *a as i32;
+        total += (d2 * d2 - d1 * d1) as i64;
+    }
+    total
+}
+
+fn blend_span_scalar(buf: &mut [u8], c: &Color) {
+    let alpha = c.a() as u32;
+    let mut i = 0;
+    while i < buf.len() {
+        util::blend(buf, i, c, alpha);
+        i += 4;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    use core::Color;
+
+    // Each function handles a whole number of 4 pixel blocks from the start of the span and returns
+    // how many bytes it covered.  The lanes of the 32 bit accumulators are emptied often enough that
+    // they can't overflow however long the span is.
+
+    const FLUSH_BLOCKS: usize = 128;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn color_sums(target: &[u8], current: &[u8], diffs: &mut [i64; 3], sums: &mut [i64; 3]) -> usize {
+        let n = target.len().min(current.len()) / 16 * 16;
+        let mut i = 0;
+        while i < n {
+            let end = n.min(i + 16 * FLUSH_BLOCKS);
+            // 16 bit lanes in r, g, b, a order; 128 blocks of at most 255 fit easily
+            let mut t16 = _mm256_setzero_si256();
+            let mut c16 = _mm256_setzero_si256();
+            while i < end {
+                let t = _mm256_cvtepu8_epi16(_mm_loadu_si128(target.as_ptr().add(i) as *const __m128i));
+                let c = _mm256_cvtepu8_epi16(_mm_loadu_si128(current.as_ptr().add(i) as *const __m128i));
+                t16 = _mm256_add_epi16(t16, t);
+                c16 = _mm256_add_epi16(c16, c);
+                i += 16;
+            }
+            let t = channel_totals(t16);
+            let c = channel_totals(c16);
+            for k in 0..3 {
+                diffs[k] += t[k] - c[k];
+                sums[k] += c[k];
+            }
+        }
+        n
+    }
+
+    // totals of the unsigned 16 bit lanes, by channel
+    #[target_feature(enable = "avx2")]
+    unsafe fn channel_totals(v: __m256i) -> [i64; 4] {
+        let mut lanes = [0u16; 16];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, v);
+        let mut totals = [0i64; 4];
+        for (j, lane) in lanes.iter().enumerate() {
+            totals[j % 4] += *lane as i64;
+        }
+        totals
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn error_delta(target: &[u8], before: &[u8], after: &[u8]) -> (usize, i64) {
+        let n = target.len().min(before.len()).min(after.len()) / 16 * 16;
+        let mut total = 0i64;
+        let mut i = 0;
+        while i < n {
+            let end = n.min(i + 16 * FLUSH_BLOCKS);
+            // each block adds at most 2 * 255^2 to a lane
+            let mut acc = _mm256_setzero_si256();
+            while i < end {
+                let t = _mm256_cvtepu8_epi16(_mm_loadu_si128(target.as_ptr().add(i) as *const __m128i));
+                let b = _mm256_cvtepu8_epi16(_mm_loadu_si128(before.as_ptr().add(i) as *const __m128i));
+                let a = _mm256_cvtepu8_epi16(_mm_loadu_si128(after.as_ptr().add(i) as *const __m128i));
+                let d1 = _mm256_sub_epi16(t, b);
+                let d2 = _mm256_sub_epi16(t, a);
+                acc = _mm256_add_epi32(acc, _mm256_sub_epi32(_mm256_madd_epi16(d2, d2), _mm256_madd_epi16(d1, d1)));
+                i += 16;
+            }
+            let mut lanes = [0i32; 8];
+            _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc);
+            total += lanes.iter().map(|&x| x as i64).sum::<i64>();
+        }
+        (n, total)
+    }
+
+    // Same arithmetic as util::blend, two pixels per 256 bit register with a 32 bit lane per channel.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn blend_span(buf: &mut [u8], c: &Color) -> usize {
+        let n = buf.len() / 16 * 16;
+        let aa = c.a() as i32;
+        let diff = _mm256_set1_epi32(255 - aa);
+        let color = _mm256_setr_epi32(
+            c.r() as i32 * aa, c.g() as i32 * aa, c.b() as i32 * aa, aa,
+            c.r() as i32 * aa, c.g() as i32 * aa, c.b() as i32 * aa, aa);
+        let ones = _mm256_set1_epi32(1);
+        let shifts = _mm256_setr_epi32(8, 8, 8, 0, 8, 8, 8, 0);
+        let mut i = 0;
+        while i < n {
+            let p = buf.as_mut_ptr().add(i);
+            let px = _mm_loadu_si128(p as *const __m128i);
+            let lo = blend_pair(_mm256_cvtepu8_epi32(px), color, diff, ones, shifts);
+            let hi = blend_pair(_mm256_cvtepu8_epi32(_mm_srli_si128(px, 8)), color, diff, ones, shifts);
+            // every lane is at most 255, so packing doesn't saturate
+            let words = _mm256_permute4x64_epi64(_mm256_packus_epi32(lo, hi), 0xd8);
+            let bytes = _mm256_packus_epi16(words, words);
+            let out = _mm256_permute4x64_epi64(bytes, 0x08);
+            _mm_storeu_si128(p as *mut __m128i, _mm256_castsi256_si128(out));
+            i += 16;
+        }
+        n
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn blend_pair(px: __m256i, color: __m256i, diff: __m256i, ones: __m256i, shifts: __m256i) -> __m256i {
+        // the color channels are weighted by the pixel's alpha, the alpha channel isn't
+        let ba = _mm256_shuffle_epi32(px, 0xff);
+        let weights = _mm256_blend_epi32(ba, ones, 0x88);
+        let x = _mm256_mullo_epi32(_mm256_mullo_epi32(px, weights), diff);
+        let q = div255(x);
+        _mm256_srlv_epi32(_mm256_add_epi32(color, q), shifts)
+    }
+
+    // exact x / 255 for unsigned 32 bit lanes: (x * 0x80808081) >> 39
+    #[target_feature(enable = "avx2")]
+    unsafe fn div255(x: __m256i) -> __m256i {
+        let m = _mm256_set1_epi32(0x80808081u32 as i32);
+        let even = _mm256_srli_epi64(_mm256_mul_epu32(x, m), 39);
+        let odd = _mm256_srli_epi64(_mm256_mul_epu32(_mm256_srli_epi64(x, 32), m), 39);
+        _mm256_blend_epi32(even, _mm256_slli_epi64(odd, 32), 0xaa)
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use rand::{Rng, SeedableRng, StdRng};
+
+    use super::*;
+
+    // pixel counts, so that every number of pixels left over after the 4 pixel blocks comes up
+    const MAX_PIXELS: usize = 40;
+    // enough 4 pixel blocks that the accumulators are flushed, and a few pixels over
+    const LONG_SPAN: usize = 4 * 128 * 4 + 7;
+
+    fn rng() -> StdRng {
+        SeedableRng::from_seed(&[38usize][..])
+    }
+
+    fn bytes(rng: &mut StdRng, n: usize) -> Vec<u8> {
+        (0..n).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn color_sums_matches_scalar() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        let mut rng = rng();
+        for n in (0..MAX_PIXELS + 1).chain(Some(LONG_SPAN)) {
+            let (target, current) = (bytes(&mut rng, 4 * n), bytes(&mut rng, 4 * n));
+            let (mut diffs, mut sums) = ([0; 3], [0; 3]);
+            color_sums(&target, &current, &mut diffs, &mut sums);
+            let (mut scalar_diffs, mut scalar_sums) = ([0; 3], [0; 3]);
+            color_sums_scalar(&target, &current, &mut scalar_diffs, &mut scalar_sums);
+            assert_eq!((diffs, sums), (scalar_diffs, scalar_sums), "{} pixels", n);
+        }
+    }
+
+    #[test]
+    fn error_delta_matches_scalar() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        let mut rng = rng();
+        for n in (0..MAX_PIXELS + 1).chain(Some(LONG_SPAN)) {
+            let target = bytes(&mut rng, 4 * n);
+            let (before, after) = (bytes(&mut rng, 4 * n), bytes(&mut rng, 4 * n));
+            assert_eq!(error_delta(&target, &before, &after), error_delta_scalar(&target, &before, &after),
+                       "{} pixels", n);
+        }
+        // the largest change in every lane
+        let (zeros, full) = (vec![0; 4 * LONG_SPAN], vec![255; 4 * LONG_SPAN]);
+        assert_eq!(error_delta(&zeros, &zeros, &full), error_delta_scalar(&zeros, &zeros, &full));
+        assert_eq!(error_delta(&zeros, &full, &zeros), error_delta_scalar(&zeros, &full, &zeros));
+    }
+
+    #[test]
+    fn blend_span_matches_scalar() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        let mut rng = rng();
+        for a in 1..256 {
+            for n in 0..MAX_PIXELS + 1 {
+                let c = Color::new(rng.gen(), rng.gen(), rng.gen(), a as u8);
+                let buf = bytes(&mut rng, 4 * n);
+                let (mut simd, mut scalar) = (buf.clone(), buf);
+                blend_span(&mut simd, &c);
+                blend_span_scalar(&mut scalar, &c);
+                assert_eq!(simd, scalar, "{:?} over {} pixels", c, n);
+            }
+            // the extremes of the division by 255
+            for &fill in &[0u8, 255] {
+                let c = Color::new(255, 0, 255, a as u8);
+                let (mut simd, mut scalar) = (vec![fill; 4 * MAX_PIXELS], vec![fill; 4 * MAX_PIXELS]);
+                blend_span(&mut simd, &c);
+                blend_span_scalar(&mut scalar, &c);
+                assert_eq!(simd, scalar, "{:?} over {}", c, fill);
+            }
+        }
+    }
+}
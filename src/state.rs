@@ -23,7 +23,7 @@ impl State {
     pub fn do_move(&mut self, worker: &mut Worker, undo: &mut State) {
         undo.copy_from(self);
 
-        self.shape.mutate(worker.w, worker.h, &mut worker.rng);
+        self.shape.mutate(worker.w, worker.h, &worker.constraints, &mut worker.rng);
         self.score = -1.0;
     }
 
@@ -0,0 +1,456 @@
+// Shape streams, for sending shapes to clients that draw them as they arrive.  A stream is a header
+// followed by any number of shapes, up to the end of the input, in one of two encodings:
+//
+// text, one record per line, fields separated by a space:
+//   primg-shapes 1 <w> <h> <background>
+//   <kind> <params...> <color>
+// params are decimal numbers and colors 8 hex digits, rrggbbaa.
+//
+// binary:
+//   header: "PRSB", version byte 1, varint w, varint h, background
+//   shape: kind byte, varint params, color
+// varints are LEB128.  params are zigzag encoded and in 1/16ths, so the binary form rounds
+// coordinates to 1/16 pixel and angles to 1/16 degree.  colors are 4 bytes, r g b a.
+//
+// w x h is the working image size that coordinates are in.  Pixel (x, y) is centered on (x, y), so
+// a renderer should shift shapes by half a pixel, as Model::svg does.  The kinds and their params,
+// numbered as in the C API, are
+//   0 triangle: x1 y1 x2 y2 x3 y3
+//   1 ellipse: x y rx ry
+//   2 rectangle: x1 y1 x2 y2
+//   3 rotated rectangle: x y sx sy angle (degrees)
+
+use std::io;
+use std::io::{BufRead, Write};
+
+use core::Color;
+use shape::Shape;
+
+pub const VERSION: u32 = 1;
+
+const TEXT_MAGIC: &str = "primg-shapes";
+const BINARY_MAGIC: &[u8; 4] = b"PRSB";
+// binary params are fixed point with this many fractional bits
+const FRACTION_BITS: u32 = 4;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Header {
+    pub w: usize,
+    pub h: usize,
+    pub bg: Color,
+}
+
+pub struct Encoder<W: Write> {
+    out: W,
+    format: Format,
+}
+
+impl<W: Write> Encoder<W> {
+    // writes the header.  nothing is buffered, so out should be if it's slow to write to.
+    pub fn new(mut out: W, format: Format, header: &Header) -> io::Result<Encoder<W>> {
+        match format {
+            Format::Text => {
+                writeln!(out, "{} {} {} {} {}", TEXT_MAGIC, VERSION, header.w, header.h, hex(&header.bg))?;
+            }
+            Format::Binary => {
+                let mut buf = BINARY_MAGIC.to_vec();
+                buf.push(VERSION as u8);
+                put_varint(&mut buf, header.w as u64);
+                put_varint(&mut buf, header.h as u64);
+                buf.extend_from_slice(&rgba(&header.bg));
+                out.write_all(&buf)?;
+            }
+        }
+        Ok(Encoder { out, format })
+    }
+
+    pub fn shape(&mut self, shape: &Shape, color: &Color) -> io::Result<()> {
+        let (kind, params) = params(shape);
+        match self.format {
+            Format::Text => {
+                let mut line = kind.to_string();
+                for p in &params {
+                    line.push_str(&format!(" {}", p));
+                }
+                writeln!(self.out, "{} {}", line, hex(color))
+            }
+            Format::Binary => {
+                let mut buf = vec![kind];
+                for p in &params {
+                    let v = (p * (1 << FRACTION_BITS) as f32).round() as i64;
+                    put_varint(&mut buf, ((v << 1) ^ (v >> 63)) as u64);
+                }
+                buf.extend_from_slice(&rgba(color));
+                self.out.write_all(&buf)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+pub struct Decoder<R: BufRead> {
+    input: R,
+    format: Format,
+    header: Header,
+}
+
+impl<R: BufRead> Decoder<R> {
+    // reads the header, working out the format from it
+    pub fn new(mut input: R) -> io::Result<Decoder<R>> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic == BINARY_MAGIC {
+            let mut version = [0u8; 1];
+            input.read_exact(&mut version)?;
+            check_version(version[0] as u32)?;
+            let w = get_varint(&mut input)? as usize;
+            let h = get_varint(&mut input)? as usize;
+            let bg = get_rgba(&mut input)?;
+            return Ok(Decoder { input, format: Format::Binary, header: Header { w, h, bg } });
+        }
+
+        let mut line = String::from_utf8_lossy(&magic).into_owned();
+        input.read_line(&mut line)?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 5 || fields[0] != TEXT_MAGIC {
+            return Err(invalid("not a shape stream"));
+        }
+        check_version(parse(fields[1])?)?;
+        let header = Header { w: parse(fields[2])?, h: parse(fields[3])?, bg: parse_hex(fields[4])? };
+        Ok(Decoder { input, format: Format::Text, header })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    // the next shape, or None at the end of the stream.  A record cut off by the end of the input is
+    // an UnexpectedEof error.
+    pub fn shape(&mut self) -> io::Result<Option<(Shape, Color)>> {
+        match self.format {
+            Format::Text => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if self.input.read_line(&mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    if !line.trim().is_empty() {
+                        break;
+                    }
+                }
+                match text_record(&line) {
+                    // the encoder ends every record with a newline
+                    Err(ref e) if !line.ends_with('\n') => {
+                        Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("truncated shape record: {}", e)))
+                    }
+                    record => record.map(Some),
+                }
+            }
+            Format::Binary => {
+                let at_end = loop {
+                    match self.input.fill_buf() {
+                        Ok(buf) => break buf.is_empty(),
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                };
+                if at_end {
+                    return Ok(None);
+                }
+                let mut kind = [0u8; 1];
+                self.input.read_exact(&mut kind)?;
+                let n = num_params(kind[0])?;
+                let mut params = [0.0; 6];
+                for p in params.iter_mut().take(n) {
+                    let z = get_varint(&mut self.input)?;
+                    let v = (z >> 1) as i64 ^ -((z & 1) as i64);
+                    *p = v as f32 / (1 << FRACTION_BITS) as f32;
+                }
+                Ok(Some((shape(kind[0], &params), get_rgba(&mut self.input)?)))
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Decoder<R> {
+    type Item = io::Result<(Shape, Color)>;
+
+    fn next(&mut self) -> Option<io::Result<(Shape, Color)>> {
+        match self.shape() {
+            Ok(Some(s)) => Some(Ok(s)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn text_record(line: &str) -> io::Result<(Shape, Color)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let kind: u8 = parse(fields[0])?;
+    let n = num_params(kind)?;
+    if fields.len() != n + 2 {
+        return Err(invalid(&format!("expected {} params for shape kind {}", n, kind)));
+    }
+    let mut params = [0.0; 6];
+    for (p, field) in params.iter_mut().zip(&fields[1..n + 1]) {
+        *p = parse(field)?;
+    }
+    Ok((shape(kind, &params), parse_hex(fields[n + 1])?))
+}
+
+// the kind and params of a shape, as described above
+pub fn params(shape: &Shape) -> (u8, Vec<f32>) {
+    match *shape {
+        Shape::Triangle { x1, y1, x2, y2, x3, y3 } => (0, vec![x1, y1, x2, y2, x3, y3]),
+        Shape::Ellipse { x, y, rx, ry } => (1, vec![x, y, rx, ry]),
+        Shape::Rectangle { x1, y1, x2, y2 } => (2, vec![x1, y1, x2, y2]),
+        Shape::RotatedRectangle { x, y, sx, sy, angle } => (3, vec![x, y, sx, sy, angle]),
+    }
+}
+
+fn num_params(kind: u8) -> io::Result<usize> {
+    match kind {
+        0 => Ok(6),
+        1 | 2 => Ok(4),
+        3 => Ok(5),
+        _ => Err(invalid(&format!("unknown shape kind {}", kind))),
+    }
+}
+
+// kind must be valid
+fn shape(kind: u8, p: &[f32; 6]) -> Shape {
+    match kind {
+        0 => Shape::Triangle { x1: p[0], y1: p[1], x2: p[2], y2: p[3], x3: p[4], y3: p[5] },
+        1 => Shape::Ellipse { x: p[0], y: p[1], rx: p[2], ry: p[3] },
+        2 => Shape::Rectangle { x1: p[0], y1: p[1], x2: p[2], y2: p[3] },
+        _ => Shape::RotatedRectangle { x: p[0], y: p[1], sx: p[2], sy: p[3], angle: p[4] },
+    }
+}
+
+fn check_version(version: u32) -> io::Result<()> {
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported shape stream version {}", version)));
+    }
+    Ok(())
+}
+
+fn hex(c: &Color) -> String {
+    format!("{:02x}{:02x}{:02x}{:02x}", c.r(), c.g(), c.b(), c.a())
+}
+
+fn parse_hex(s: &str) -> io::Result<Color> {
+    if s.len() != 8 {
+        return Err(invalid(&format!("invalid color: {}", s)));
+    }
+    let v = u32::from_str_radix(s, 16).map_err(|_| invalid(&format!("invalid color: {}", s)))?;
+    Ok(Color::new((v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8))
+}
+
+fn parse<T: ::std::str::FromStr>(s: &str) -> io::Result<T> {
+    s.parse().map_err(|_| invalid(&format!("invalid number: {}", s)))
+}
+
+fn rgba(c: &Color) -> [u8; 4] {
+    [c.r(), c.g(), c.b(), c.a()]
+}
+
+fn get_rgba<R: BufRead>(input: &mut R) -> io::Result<Color> {
+    let mut c = [0u8; 4];
+    input.read_exact(&mut c)?;
+    Ok(Color::from(&c))
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn get_varint<R: BufRead>(input: &mut R) -> io::Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut b = [0u8; 1];
+        input.read_exact(&mut b)?;
+        v |= ((b[0] & 0x7f) as u64) << shift;
+        if b[0] & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn header() -> Header {
+        Header { w: 256, h: 171, bg: Color::new(12, 34, 56, 255) }
+    }
+
+    // every kind, with negative coordinates and params between sixteenths
+    fn shapes() -> Vec<(Shape, Color)> {
+        vec![
+            (Shape::Triangle { x1: -16.0, y1: 3.25, x2: 100.03, y2: -7.97, x3: 255.5, y3: 170.0 },
+             Color::new(255, 0, 128, 128)),
+            (Shape::Ellipse { x: 12.3, y: -4.1, rx: 30.0, ry: 0.5 }, Color::new(1, 2, 3, 4)),
+            (Shape::Rectangle { x1: -3.0, y1: 0.0, x2: 17.81, y2: 99.99 }, Color::new(200, 201, 202, 255)),
+            (Shape::RotatedRectangle { x: 50.0, y: 60.0, sx: 12.4, sy: 3.0, angle: -359.97 }, Color::new(0, 0, 0, 1)),
+        ]
+    }
+
+    fn encode(format: Format, shapes: &[(Shape, Color)]) -> Vec<u8> {
+        let mut encoder = Encoder::new(vec![], format, &header()).expect("wtf");
+        for s in shapes {
+            encoder.shape(&s.0, &s.1).expect("wtf");
+        }
+        encoder.into_inner()
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Vec<(Shape, Color)>> {
+        let decoder = Decoder::new(Cursor::new(bytes))?;
+        assert_eq!(*decoder.header(), header());
+        decoder.collect()
+    }
+
+    fn header_error(bytes: &[u8]) -> io::Error {
+        match Decoder::new(Cursor::new(bytes)) {
+            Ok(_) => panic!("decoded a bad header"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn text_round_trips_exactly() {
+        let bytes = encode(Format::Text, &shapes());
+        assert_eq!(Decoder::new(Cursor::new(&bytes)).expect("wtf").format(), Format::Text);
+        let decoded = decode(&bytes).expect("wtf");
+        assert_eq!(decoded.len(), shapes().len());
+        for ((shape, color), (expected, expected_color)) in decoded.into_iter().zip(shapes()) {
+            assert_eq!(params(&shape), params(&expected));
+            assert_eq!(color, expected_color);
+        }
+    }
+
+    #[test]
+    fn binary_round_trips_to_sixteenths() {
+        let bytes = encode(Format::Binary, &shapes());
+        assert_eq!(Decoder::new(Cursor::new(&bytes)).expect("wtf").format(), Format::Binary);
+        let decoded = decode(&bytes).expect("wtf");
+        assert_eq!(decoded.len(), shapes().len());
+        for ((shape, color), (expected, expected_color)) in decoded.into_iter().zip(shapes()) {
+            let (kind, ps) = params(&shape);
+            let (expected_kind, expected_ps) = params(&expected);
+            assert_eq!(kind, expected_kind);
+            for (p, e) in ps.iter().zip(&expected_ps) {
+                assert_eq!(*p, (e * 16.0).round() / 16.0);
+                assert!((p - e).abs() <= 1.0 / 32.0);
+            }
+            assert_eq!(color, expected_color);
+        }
+        // already on sixteenths, so encoding again changes nothing
+        let again: Vec<(Shape, Color)> = decode(&bytes).expect("wtf");
+        assert_eq!(encode(Format::Binary, &again), bytes);
+    }
+
+    // fails every other read with Interrupted, as a read cut short by a signal does
+    struct Interrupting {
+        inner: Cursor<Vec<u8>>,
+        interrupt: bool,
+    }
+
+    impl io::Read for Interrupting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn interrupted_reads_are_retried() {
+        for &format in &[Format::Text, Format::Binary] {
+            let bytes = encode(format, &shapes());
+            let input = Interrupting { inner: Cursor::new(bytes), interrupt: false };
+            let decoder = Decoder::new(io::BufReader::with_capacity(3, input)).expect("wtf");
+            let decoded: Vec<(Shape, Color)> = decoder.collect::<io::Result<_>>().expect("wtf");
+            assert_eq!(decoded.len(), shapes().len());
+        }
+    }
+
+    #[test]
+    fn empty_stream_has_no_shapes() {
+        for &format in &[Format::Text, Format::Binary] {
+            assert!(decode(&encode(format, &[])).expect("wtf").is_empty());
+        }
+    }
+
+    #[test]
+    fn truncated_record_is_unexpected_eof() {
+        for &format in &[Format::Text, Format::Binary] {
+            let mut bytes = encode(format, &shapes());
+            let n = bytes.len();
+            // the text form loses its newline and color, the binary form part of its color
+            bytes.truncate(n - 3);
+            let decoded: Vec<io::Result<(Shape, Color)>> = Decoder::new(Cursor::new(&bytes)).expect("wtf").collect();
+            assert_eq!(decoded.len(), shapes().len());
+            assert!(decoded[..shapes().len() - 1].iter().all(|r| r.is_ok()));
+            let err = decoded.last().expect("wtf").as_ref().expect_err("wtf");
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{:?}", format);
+        }
+        let header = encode(Format::Binary, &[]);
+        let err = header_error(&header[..header.len() - 1]);
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn unknown_kind_is_invalid() {
+        let mut text = encode(Format::Text, &[]);
+        text.extend_from_slice(b"9 1 2 3 4 ff00ff80\n");
+        let mut binary = encode(Format::Binary, &[]);
+        binary.extend_from_slice(&[9, 0, 0, 0, 0, 255, 0, 255, 128]);
+        for bytes in &[text, binary] {
+            let err = decode(bytes).expect_err("wtf");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn unknown_version_is_invalid() {
+        let mut binary = encode(Format::Binary, &[]);
+        binary[4] = VERSION as u8 + 1;
+        let text = format!("primg-shapes {} 256 171 0c2238ff\n", VERSION + 1).into_bytes();
+        for bytes in &[text, binary] {
+            let err = header_error(bytes);
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        let err = header_error(b"GIF89a");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,185 @@
+// Compact SVG output.  Model::svg mirrors the shapes one to one; this writes the same picture in
+// fewer bytes: coordinates stay in working pixels with a viewBox doing the scaling (so the picture
+// also fills whatever it's placed in), numbers are rounded to a given precision, rotated rectangles
+// are polygons, and consecutive shapes with the same alpha share a group's fill-opacity.
+
+use core::Color;
+use shape::Shape;
+use util;
+
+#[derive(Debug, Copy, Clone)]
+pub struct SvgOptions {
+    // decimal places kept in coordinates, in working pixels
+    pub precision: usize,
+    // no line breaks, and no leading zeros on fractions
+    pub minify: bool,
+}
+
+impl Default for SvgOptions {
+    fn default() -> SvgOptions {
+        SvgOptions { precision: 2, minify: false }
+    }
+}
+
+pub fn optimized(w: usize, h: usize, bg: &Color, shapes: &[(&Shape, Color)], options: &SvgOptions) -> String {
+    let f = Formatter(*options);
+    let mut lines = vec![];
+    // pixel (x, y) is centered on (x, y)
+    lines.push(format!("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{0} {0} {1} {2}\">", f.num(-0.5), w, h));
+    lines.push(format!("<rect x=\"{0}\" y=\"{0}\" width=\"{1}\" height=\"{2}\" fill=\"{3}\"/>",
+                       f.num(-0.5), w, h, hex(bg)));
+
+    let mut i = 0;
+    while i < shapes.len() {
+        let alpha = shapes[i].1.a();
+        let run = shapes[i..].iter().take_while(|s| s.1.a() == alpha).count();
+        // a group only pays off for more than one shape
+        if alpha == 255 || run == 1 {
+            for &(shape, color) in &shapes[i..i + run] {
+                lines.push(f.element(shape, &color, alpha != 255));
+            }
+        } else {
+            lines.push(format!("<g fill-opacity=\"{}\">", f.opacity(alpha)));
+            for &(shape, color) in &shapes[i..i + run] {
+                lines.push(f.element(shape, &color, false));
+            }
+            lines.push(String::from("</g>"));
+        }
+        i += run;
+    }
+
+    lines.push(String::from("</svg>"));
+    lines.join(if options.minify { "" } else { "\n" })
+}
+
+struct Formatter(SvgOptions);
+
+impl Formatter {
+    fn element(&self, shape: &Shape, color: &Color, with_opacity: bool) -> String {
+        let mut attrs = format!("fill=\"{}\"", hex(color));
+        if with_opacity {
+            attrs.push_str(&format!(" fill-opacity=\"{}\"", self.opacity(color.a())));
+        }
+        match *shape {
+            Shape::Ellipse { x, y, rx, ry } => {
+                format!("<ellipse {} cx=\"{}\" cy=\"{}\" rx=\"{}\" ry=\"{}\"/>",
+                        attrs, self.num(x), self.num(y), self.num(rx), self.num(ry))
+            }
+            Shape::Rectangle { x1, y1, x2, y2 } => {
+                format!("<rect {} x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>",
+                        attrs, self.num(x1 - 0.5), self.num(y1 - 0.5), self.num(x2 - x1 + 1.0), self.num(y2 - y1 + 1.0))
+            }
+            Shape::Triangle { .. } | Shape::RotatedRectangle { .. } => {
+                let points: Vec<String> = shape.polygon().iter()
+                    .map(|&(x, y)| format!("{},{}", self.num(x), self.num(y)))
+                    .collect();
+                format!("<polygon {} points=\"{}\"/>", attrs, points.join(" "))
+            }
+        }
+    }
+
+    // v to the precision, without trailing zeros
+    fn num(&self, v: f32) -> String {
+        self.trim(format!("{:.*}", self.0.precision, v))
+    }
+
+    fn opacity(&self, alpha: u8) -> String {
+        self.trim(format!("{:.3}", alpha as f32 / 255.0))
+    }
+
+    fn trim(&self, s: String) -> String {
+        let mut s = util::trim_zeros(s);
+        if self.0.minify {
+            if s.starts_with("0.") {
+                s.remove(0);
+            } else if s.starts_with("-0.") {
+                s.remove(1);
+            }
+        }
+        s
+    }
+}
+
+// "#rgb" where that's the same color, otherwise "#rrggbb"
+fn hex(c: &Color) -> String {
+    let (r, g, b) = (c.r(), c.g(), c.b());
+    if r % 17 == 0 && g % 17 == 0 && b % 17 == 0 {
+        format!("#{:x}{:x}{:x}", r / 17, g / 17, b / 17)
+    } else {
+        util::hex(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: f32) -> Shape {
+        Shape::Rectangle { x1: x, y1: 1.0, x2: x + 2.0, y2: 3.0 }
+    }
+
+    fn formatter(precision: usize, minify: bool) -> Formatter {
+        Formatter(SvgOptions { precision, minify })
+    }
+
+    #[test]
+    fn groups_runs_of_the_same_alpha() {
+        let shapes = [rect(0.0), rect(1.0), rect(2.0), rect(3.0), rect(4.0), rect(5.0)];
+        let alphas = [128, 128, 64, 255, 255, 128];
+        let colored: Vec<(&Shape, Color)> = shapes.iter().zip(alphas.iter())
+            .map(|(shape, &a)| (shape, Color::new(255, 0, 0, a)))
+            .collect();
+        let svg = optimized(10, 10, &Color::new(255, 255, 255, 255), &colored, &SvgOptions::default());
+        let lines: Vec<&str> = svg.lines().collect();
+        assert_eq!(&lines[2..], &[
+            "<g fill-opacity=\"0.502\">",
+            "<rect fill=\"#f00\" x=\"-0.5\" y=\"0.5\" width=\"3\" height=\"3\"/>",
+            "<rect fill=\"#f00\" x=\"0.5\" y=\"0.5\" width=\"3\" height=\"3\"/>",
+            "</g>",
+            "<rect fill=\"#f00\" fill-opacity=\"0.251\" x=\"1.5\" y=\"0.5\" width=\"3\" height=\"3\"/>",
+            "<rect fill=\"#f00\" x=\"2.5\" y=\"0.5\" width=\"3\" height=\"3\"/>",
+            "<rect fill=\"#f00\" x=\"3.5\" y=\"0.5\" width=\"3\" height=\"3\"/>",
+            "<rect fill=\"#f00\" fill-opacity=\"0.502\" x=\"4.5\" y=\"0.5\" width=\"3\" height=\"3\"/>",
+            "</svg>",
+        ][..]);
+    }
+
+    #[test]
+    fn hex_shortens_only_when_exact() {
+        assert_eq!(hex(&Color::new(0x11, 0x22, 0xff, 128)), "#12f");
+        assert_eq!(hex(&Color::new(0, 0, 0, 255)), "#000");
+        assert_eq!(hex(&Color::new(0x11, 0x22, 0xfe, 128)), "#1122fe");
+        assert_eq!(hex(&Color::new(0x10, 0x22, 0xff, 128)), "#1022ff");
+    }
+
+    #[test]
+    fn numbers_round_and_trim() {
+        let f = formatter(2, false);
+        assert_eq!(f.num(1.0), "1");
+        assert_eq!(f.num(0.25), "0.25");
+        assert_eq!(f.num(1.257), "1.26");
+        assert_eq!(f.num(-0.001), "0");
+        assert_eq!(f.opacity(128), "0.502");
+        assert_eq!(formatter(1, false).num(1.26), "1.3");
+        assert_eq!(formatter(1, false).num(-0.04), "0");
+        assert_eq!(formatter(0, false).num(10.0), "10");
+        assert_eq!(formatter(0, false).num(9.7), "10");
+    }
+
+    #[test]
+    fn minify_drops_leading_zeros() {
+        let f = formatter(2, true);
+        assert_eq!(f.num(0.25), ".25");
+        assert_eq!(f.num(-0.25), "-.25");
+        assert_eq!(f.num(1.5), "1.5");
+        assert_eq!(f.num(-10.05), "-10.05");
+        assert_eq!(f.num(-0.001), "0");
+        assert_eq!(f.opacity(128), ".502");
+
+        let shapes = [rect(0.0)];
+        let colored: Vec<(&Shape, Color)> = shapes.iter().map(|s| (s, Color::new(1, 2, 3, 128))).collect();
+        let svg = optimized(4, 4, &Color::new(0, 0, 0, 255), &colored, &SvgOptions { precision: 2, minify: true });
+        assert!(!svg.contains('\n'));
+        assert!(svg.contains("viewBox=\"-.5 -.5 4 4\""));
+    }
+}
@@ -9,6 +9,7 @@ use std::f32::consts::PI;
 
 use core::Color;
 use scanline::Scanline;
+use simd;
 
 pub fn load_image(filepath: &str) -> ImageResult<DynamicImage> {
     image::open(filepath)
@@ -37,37 +38,30 @@ pub fn degrees(radians: f32) -> f32 {
     radians * 180.0 / PI
 }
 
-pub fn draw_lines(buf: &mut [u8], w: usize, h: usize, a: &Color, lines: &[Scanline]) {
-    let aa = a.a() as u32;
-    let ar = a.r() as u32 * aa;
-    let ag = a.g() as u32 * aa;
-    let ab = a.b() as u32 * aa;
-    unsafe {
-        for line in lines {
-            let mut i = 4 * (line.y * w + line.x1) as isize;
-            for _ in line.x1..(line.x2 + 1) {
-                let p = buf.as_mut_ptr();
-                let p0 = p.offset(i + 0);
-                let p1 = p.offset(i + 1);
-                let p2 = p.offset(i + 2);
-                let p3 = p.offset(i + 3);
-
-                let ba = *p3 as u32;
-                let br = *p0 as u32 * ba;
-                let bg = *p1 as u32 * ba;
-                let bb = *p2 as u32 * ba;
-                let diff = 255 - aa;
-                *p0 = ((ar + br * diff / 255) >> 8) as u8;
-                *p1 = ((ag + bg * diff / 255) >> 8) as u8;
-                *p2 = ((ab + bb * diff / 255) >> 8) as u8;
-                *p3 = (aa + ba * diff / 255) as u8;
-
-                i += 4;
-            }
-        }
+pub fn draw_lines(buf: &mut [u8], w: usize, _h: usize, a: &Color, lines: &[Scanline]) {
+    for line in lines {
+        let n = (line.x2 + 1).saturating_sub(line.x1);
+        let i = 4 * (line.y * w + line.x1);
+        simd::blend_span(&mut buf[i..i + 4 * n], a);
     }
 }
 
+// draw_lines for a single pixel with its own alpha
+pub fn blend(buf: &mut [u8], i: usize, c: &Color, alpha: u32) {
+    let ar = c.r() as u32 * alpha;
+    let ag = c.g() as u32 * alpha;
+    let ab = c.b() as u32 * alpha;
+    let ba = buf[i + 3] as u32;
+    let br = buf[i] as u32 * ba;
+    let bg = buf[i + 1] as u32 * ba;
+    let bb = buf[i + 2] as u32 * ba;
+    let diff = 255 - alpha;
+    buf[i] = ((ar + br * diff / 255) >> 8) as u8;
+    buf[i + 1] = ((ag + bg * diff / 255) >> 8) as u8;
+    buf[i + 2] = ((ab + bb * diff / 255) >> 8) as u8;
+    buf[i + 3] = (alpha + ba * diff / 255) as u8;
+}
+
 pub fn erase(buf: &mut [u8], color: &Color) {
     let mut i = 0;
     let len = buf.len();
@@ -87,3 +81,24 @@ pub fn rotate(x: f32, y: f32, theta: f32) -> (f32, f32) {
     let ry = x * sin + y * cos;
     (rx, ry)
 }
+
+// "#rrggbb"
+pub fn hex(c: &Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", c.r(), c.g(), c.b())
+}
+
+// a formatted decimal number without trailing zeros, and "0" for "-0"
+pub fn trim_zeros(mut s: String) -> String {
+    if s.contains('.') {
+        while s.ends_with('0') {
+            s.pop();
+        }
+        if s.ends_with('.') {
+            s.pop();
+        }
+    }
+    if s == "-0" {
+        s = String::from("0");
+    }
+    s
+}
@@ -0,0 +1,214 @@
+// PDF and EPS output, for print.  The page is the output size in points, drawn like Model::svg:
+// shapes in working pixels, scaled up and shifted by half a pixel, and the y axis flipped since
+// both put the origin at the bottom left.
+//
+// PDF gets opacity from an ExtGState per alpha, on a page that is a transparency group.  PostScript
+// has no transparency, so EPS sets it with the SetTransparency pdfmark, which Distiller and
+// Ghostscript carry into the PDFs they make.  Other interpreters draw the shapes opaque.
+
+use core::Color;
+use shape::Shape;
+use util;
+
+// control point distance for a quarter ellipse as a cubic Bézier curve
+const KAPPA: f32 = 0.552_284_8;
+
+pub fn pdf(width: usize, height: usize, scale: f32, bg: &Color, shapes: &[(&Shape, Color)]) -> Vec<u8> {
+    let mut alphas: Vec<u8> = shapes.iter().map(|s| s.1.a()).collect();
+    alphas.sort();
+    alphas.dedup();
+
+    let mut content = vec![];
+    content.push(format!("{} rg 0 0 {} {} re f", rgb(bg), width, height));
+    content.push(format!("{} cm", matrix(height, scale)));
+    let mut alpha = None;
+    for &(shape, color) in shapes {
+        if alpha != Some(color.a()) {
+            alpha = Some(color.a());
+            let i = alphas.binary_search(&color.a()).expect("wtf");
+            content.push(format!("/GS{} gs", i));
+        }
+        content.push(format!("{} rg {} f", rgb(&color), path(shape)));
+    }
+    let content = content.join("\n");
+
+    let states: Vec<String> = alphas.iter().enumerate()
+        .map(|(i, &a)| format!("/GS{} << /Type /ExtGState /ca {} >>", i, num(a as f32 / 255.0)))
+        .collect();
+    let objects = [
+        String::from("<< /Type /Catalog /Pages 2 0 R >>"),
+        String::from("<< /Type /Pages /Kids [3 0 R] /Count 1 >>"),
+        format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /ExtGState << {} >> >> \
+                 /Group << /Type /Group /S /Transparency /CS /DeviceRGB >> /Contents 4 0 R >>",
+                width, height, states.join(" ")),
+        format!("<< /Length {} >>\nstream\n{}\nendstream", content.len(), content),
+    ];
+
+    // the binary comment tells transfer programs the file isn't text
+    let mut out = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+    let mut offsets = vec![];
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+    }
+    let xref = out.len();
+    out.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend_from_slice(format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                                  objects.len() + 1, xref).as_bytes());
+    out
+}
+
+pub fn eps(width: usize, height: usize, scale: f32, bg: &Color, shapes: &[(&Shape, Color)]) -> String {
+    let mut lines = vec![
+        String::from("%!PS-Adobe-3.0 EPSF-3.0"),
+        format!("%%BoundingBox: 0 0 {} {}", width, height),
+        String::from("%%Creator: primg"),
+        String::from("%%LanguageLevel: 2"),
+        String::from("%%EndComments"),
+        // pdfmark does nothing where it isn't defined
+        String::from("/pdfmark where { pop } { userdict /pdfmark /cleartomark load put } ifelse"),
+        String::from("8 dict begin"),
+        String::from("/m /moveto load def /l /lineto load def /c /curveto load def"),
+        String::from("/rgb /setrgbcolor load def /f { closepath fill } bind def"),
+        String::from("gsave"),
+        format!("{} rgb 0 0 {} {} rectfill", rgb(bg), width, height),
+        format!("[{}] concat", matrix(height, scale)),
+    ];
+    let mut alpha = None;
+    for &(shape, color) in shapes {
+        if alpha != Some(color.a()) {
+            alpha = Some(color.a());
+            lines.push(format!("[ /ca {} /SetTransparency pdfmark", num(color.a() as f32 / 255.0)));
+        }
+        lines.push(format!("{} rgb newpath {} f", rgb(&color), path(shape)));
+    }
+    if alpha.is_some() {
+        lines.push(String::from("[ /ca 1 /SetTransparency pdfmark"));
+    }
+    lines.push(String::from("grestore"));
+    lines.push(String::from("end"));
+    lines.push(String::from("showpage"));
+    lines.push(String::from("%%EOF"));
+    lines.join("\n") + "\n"
+}
+
+// working pixels to page points
+fn matrix(height: usize, scale: f32) -> String {
+    format!("{} 0 0 {} {} {}", num(scale), num(-scale), num(scale / 2.0), num(height as f32 - scale / 2.0))
+}
+
+// The outline of shape, left for the caller to close and fill.  m, l and c are PDF's operators,
+// and defined in the EPS prolog as moveto, lineto and curveto.
+fn path(shape: &Shape) -> String {
+    match *shape {
+        Shape::Ellipse { x, y, rx, ry } => {
+            let (kx, ky) = (rx * KAPPA, ry * KAPPA);
+            let mut ops = vec![format!("{} {} m", num(x + rx), num(y))];
+            // one curve per quadrant, from angle 0 round through 90, 180 and 270 degrees
+            let quadrants = [
+                (x + rx, y + ky, x + kx, y + ry, x, y + ry),
+                (x - kx, y + ry, x - rx, y + ky, x - rx, y),
+                (x - rx, y - ky, x - kx, y - ry, x, y - ry),
+                (x + kx, y - ry, x + rx, y - ky, x + rx, y),
+            ];
+            for &(x1, y1, x2, y2, x3, y3) in &quadrants {
+                ops.push(format!("{} {} {} {} {} {} c", num(x1), num(y1), num(x2), num(y2), num(x3), num(y3)));
+            }
+            ops.join(" ")
+        }
+        _ => {
+            let points = shape.polygon();
+            let mut ops = vec![format!("{} {} m", num(points[0].0), num(points[0].1))];
+            for &(x, y) in &points[1..] {
+                ops.push(format!("{} {} l", num(x), num(y)));
+            }
+            ops.join(" ")
+        }
+    }
+}
+
+fn rgb(c: &Color) -> String {
+    format!("{} {} {}", num(c.r() as f32 / 255.0), num(c.g() as f32 / 255.0), num(c.b() as f32 / 255.0))
+}
+
+// 3 decimal places, without trailing zeros
+fn num(v: f32) -> String {
+    util::trim_zeros(format!("{:.3}", v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shapes() -> Vec<(Shape, Color)> {
+        vec![
+            (Shape::Triangle { x1: 1.0, y1: 2.0, x2: 9.5, y2: 3.0, x3: 4.0, y3: 8.25 }, Color::new(200, 10, 30, 128)),
+            (Shape::Ellipse { x: 5.0, y: 5.0, rx: 3.0, ry: 2.0 }, Color::new(0, 0, 255, 64)),
+            (Shape::Rectangle { x1: 0.0, y1: 1.0, x2: 4.0, y2: 3.0 }, Color::new(17, 34, 51, 128)),
+            (Shape::RotatedRectangle { x: 6.0, y: 4.0, sx: 2.0, sy: 1.0, angle: 30.0 }, Color::new(255, 255, 0, 255)),
+        ]
+    }
+
+    fn pdf_of(shapes: &[(Shape, Color)]) -> Vec<u8> {
+        let shapes: Vec<(&Shape, Color)> = shapes.iter().map(|s| (&s.0, s.1)).collect();
+        pdf(30, 20, 2.0, &Color::new(255, 255, 255, 255), &shapes)
+    }
+
+    fn find(bytes: &[u8], s: &str, from: usize) -> usize {
+        from + bytes[from..].windows(s.len()).position(|w| w == s.as_bytes()).expect(s)
+    }
+
+    // the decimal number at the start of bytes
+    fn number(bytes: &[u8]) -> usize {
+        let digits: Vec<u8> = bytes.iter().cloned().take_while(u8::is_ascii_digit).collect();
+        String::from_utf8(digits).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn pdf_offsets_and_length_are_exact() {
+        let out = pdf_of(&shapes());
+
+        let xref = number(&out[find(&out, "startxref\n", 0) + 10..]);
+        assert!(out[xref..].starts_with(b"xref\n0 5\n0000000000 65535 f \n"));
+        let entries = find(&out, " f \n", xref) + 4;
+        for i in 0..4 {
+            let offset = number(&out[entries + i * 20..]);
+            assert!(out[offset..].starts_with(format!("{} 0 obj\n", i + 1).as_bytes()), "object {}", i + 1);
+        }
+
+        let length = number(&out[find(&out, "/Length ", 0) + 8..]);
+        let start = find(&out, "stream\n", 0) + 7;
+        assert!(out[start + length..].starts_with(b"\nendstream"));
+        assert_eq!(find(&out, "\nendstream", start), start + length);
+    }
+
+    #[test]
+    fn pdf_has_a_state_per_alpha() {
+        let out = String::from_utf8_lossy(&pdf_of(&shapes())).into_owned();
+        // 64, 128 and 255, with 128 used twice
+        assert!(out.contains("/GS0 << /Type /ExtGState /ca 0.251 >>"));
+        assert!(out.contains("/GS1 << /Type /ExtGState /ca 0.502 >>"));
+        assert!(out.contains("/GS2 << /Type /ExtGState /ca 1 >>"));
+        assert!(!out.contains("/GS3"));
+        assert_eq!(out.matches("/ExtGState /ca").count(), 3);
+
+        let out = String::from_utf8_lossy(&pdf_of(&[])).into_owned();
+        assert!(!out.contains("/GS0"));
+    }
+
+    #[test]
+    fn eps_bounds_the_page_and_restores_opacity() {
+        let shapes = shapes();
+        let shapes: Vec<(&Shape, Color)> = shapes.iter().map(|s| (&s.0, s.1)).collect();
+        let out = eps(30, 20, 2.0, &Color::new(255, 255, 255, 255), &shapes);
+        assert!(out.starts_with("%!PS-Adobe-3.0 EPSF-3.0\n%%BoundingBox: 0 0 30 20\n"));
+        assert!(out.ends_with("showpage\n%%EOF\n"));
+        let marks: Vec<&str> = out.lines().filter(|l| l.ends_with("/SetTransparency pdfmark")).collect();
+        assert_eq!(marks, ["[ /ca 0.502 /SetTransparency pdfmark", "[ /ca 0.251 /SetTransparency pdfmark",
+                           "[ /ca 0.502 /SetTransparency pdfmark", "[ /ca 1 /SetTransparency pdfmark",
+                           "[ /ca 1 /SetTransparency pdfmark"]);
+    }
+}
@@ -1,63 +1,122 @@
 use rand;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
-use core::Pixels;
+use core::{Color, Pixels};
+use integral::RowSums;
+use palette::Palette;
 use scanline::Scanline;
-use shape::{Shape, ShapeType};
+use shape::{Constraints, Shape, ShapeType};
 use state::State;
 
 pub struct Worker {
     pub w: usize,
     pub h: usize,
     pub target: Arc<Pixels>,
-    pub current: Arc<RwLock<Pixels>>,
+    // snapshot of the model's image, only held during a step
+    pub current: Option<Arc<Pixels>>,
+    pub row_sums: Option<Arc<RowSums>>,
+    pub palette: Option<Arc<Palette>>,
     pub buffer: Pixels,
     pub rng: rand::StdRng,
     pub scanlines: Vec<Scanline>,
     pub score: f32,
+    pub constraints: Constraints,
+    pub screen: Option<Screen>,
 }
 
 unsafe impl Sync for Worker {}
 
+// Half resolution copies of the images for screening random candidates.  Of each batch, only the
+// promote best candidates at this level are scored at full resolution.
+pub struct Screen {
+    pub target: Arc<Pixels>,
+    // snapshot of the model's half image, only held during a step
+    pub current: Option<Arc<Pixels>>,
+    pub score: f32,
+    pub promote: usize,
+    buffer: Pixels,
+    scanlines: Vec<Scanline>,
+}
+
+impl Screen {
+    pub fn new(target: Arc<Pixels>, promote: usize) -> Screen {
+        let buffer = Pixels::new(target.w, target.h);
+        let scanlines = Scanline::buffer(target.h);
+        Screen { target, current: None, score: -1.0, promote, buffer, scanlines }
+    }
+}
+
 impl Worker {
-    pub fn new(target: Arc<Pixels>, current: Arc<RwLock<Pixels>>) -> Worker {
+    pub fn new(target: Arc<Pixels>, palette: Option<Arc<Palette>>, screen: Option<Screen>) -> Worker {
         let w = target.w;
         let h = target.h;
         let buffer = Pixels::new(w, h);
         let rng = rand::StdRng::new().expect("wtf");
         let scanlines = Scanline::buffer(h);
         let score = -1.0;
-        Worker { w, h, target, current, buffer, rng, scanlines, score }
+        let current = None;
+        let row_sums = None;
+        let constraints = Constraints::default();
+        Worker { w, h, target, current, row_sums, palette, buffer, rng, scanlines, score, constraints, screen }
     }
 
-    pub fn init(&mut self, score: f32) {
+    // small is the half image and its score, when screening
+    pub fn init(&mut self, current: Arc<Pixels>, row_sums: Option<Arc<RowSums>>, small: Option<(Arc<Pixels>, f32)>,
+                score: f32, constraints: Constraints) {
+        self.current = Some(current);
+        self.row_sums = row_sums;
+        if let (Some(screen), Some((current, score))) = (self.screen.as_mut(), small) {
+            screen.current = Some(current);
+            screen.score = score;
+        }
         self.score = score;
+        self.constraints = constraints;
+    }
+
+    // drops the step's snapshots, so the model can update its images in place
+    pub fn release(&mut self) {
+        self.current = None;
+        self.row_sums = None;
+        if let Some(screen) = self.screen.as_mut() {
+            screen.current = None;
+        }
     }
 
     pub fn energy(&mut self, shape: &Shape, alpha: u8) -> f32 {
         let lines = shape.rasterize(self.w, self.h, &mut self.scanlines);
-        let current = self.current.read().unwrap();
-        let color = current.compute_color(self.target.as_ref(), lines, alpha);
-        self.buffer.copy_lines(&current, lines);
-        self.buffer.draw_lines(&color, lines);
-        Pixels::difference_partial(&self.target, &current, &self.buffer, self.score, lines)
+        let current = self.current.as_ref().expect("wtf");
+        // rectangles are scored from the row sums when the model provides them
+        let totals = match *shape {
+            Shape::Rectangle { .. } => self.row_sums.as_ref().map(|row_sums| row_sums.totals(lines)),
+            _ => None,
+        };
+        let mut color = match totals {
+            Some(ref totals) => totals.color(alpha),
+            None => current.compute_color(self.target.as_ref(), lines, alpha),
+        };
+        if let Some(ref palette) = self.palette {
+            color = palette.nearest(&color);
+        }
+        if let Some(ref totals) = totals {
+            let ni = (self.w * self.h * 4) as f64;
+            let total = (self.score as f64 * 255.0).powi(2) * ni + totals.error_delta(&color);
+            return ((total.max(0.0) / ni).sqrt() / 255.0) as f32;
+        }
+        draw_and_score(&self.target, current, &mut self.buffer, &color, lines, self.score)
     }
 
-    pub fn best_hill_climb_state(&mut self, t: ShapeType, a: u8, n: u32, m: u8) -> State {
-        let mut state = self.best_random_state(t, a, n);
-        self.hill_climb(&mut state, 100);
-        let mut best_state = state.clone();
-        let mut best_energy = best_state.energy(self);
-        for _ in 1..m {
-            state = self.best_random_state(t, a, n);
-            self.hill_climb(&mut state, 100);
-            let energy = state.energy(self);
-            if energy < best_energy {
-                best_energy = energy;
-                best_state.copy_from(&state);
-            }
+    // energy at the screening level
+    fn screen_energy(&mut self, shape: &Shape, alpha: u8) -> f32 {
+        let screen = self.screen.as_mut().expect("wtf");
+        let current = screen.current.as_ref().expect("wtf");
+        // pixel centers c map to (c + 0.5) * 0.5 - 0.5, so the same pixels are covered at both levels
+        let shape = shape.scaled(0.5).translated(-0.25, -0.25);
+        let lines = shape.rasterize(screen.target.w, screen.target.h, &mut screen.scanlines);
+        let mut color = current.compute_color(&screen.target, lines, alpha);
+        if let Some(ref palette) = self.palette {
+            color = palette.nearest(&color);
         }
-        best_state
+        draw_and_score(&screen.target, current, &mut screen.buffer, &color, lines, screen.score)
     }
 
     pub fn hill_climb(&mut self, state: &mut State, max_age: i32) {
@@ -68,7 +127,7 @@ impl Worker {
         while age < max_age {
             state.do_move(self, &mut undo);
             let energy = state.energy(self);
-            if energy > best_energy {
+            if energy >= best_energy {
                 state.copy_from(&undo);
             } else {
                 best_energy = energy;
@@ -81,6 +140,14 @@ impl Worker {
     }
 
     pub fn best_random_state(&mut self, t: ShapeType, a: u8, n: u32) -> State {
+        let promote = match self.screen {
+            // rectangles scored from row sums are already cheaper than screening
+            Some(ref screen) if screen.current.is_some() && self.row_sums.is_none() => screen.promote,
+            _ => n as usize,
+        };
+        if promote < n as usize {
+            return self.best_screened_state(t, a, n, promote);
+        }
         let mut best_state = self.random_state(t, a);
         let mut best_energy = best_state.energy(self);
         for _ in 1..n {
@@ -94,8 +161,42 @@ impl Worker {
         best_state
     }
 
+    // best of n random states, of which only the promote best at the screening level are scored at
+    // full resolution
+    fn best_screened_state(&mut self, t: ShapeType, a: u8, n: u32, promote: usize) -> State {
+        let mut candidates: Vec<(f32, State)> = Vec::with_capacity(promote + 1);
+        for _ in 0..n {
+            let state = self.random_state(t, a);
+            let energy = self.screen_energy(&state.shape, a);
+            if candidates.len() == promote && energy >= candidates[promote - 1].0 {
+                continue;
+            }
+            let i = candidates.iter().position(|c| energy < c.0).unwrap_or(candidates.len());
+            candidates.insert(i, (energy, state));
+            candidates.truncate(promote);
+        }
+        let mut best_state = None;
+        let mut best_energy = 0.0;
+        for (_, mut state) in candidates {
+            let energy = state.energy(self);
+            if best_state.is_none() || energy < best_energy {
+                best_energy = energy;
+                best_state = Some(state);
+            }
+        }
+        best_state.expect("wtf")
+    }
+
     pub fn random_state(&mut self, t: ShapeType, alpha: u8) -> State {
-        let shape = Shape::random(t, self.w, self.h, &mut self.rng);
+        let shape = Shape::random(t, self.w, self.h, &self.constraints, &mut self.rng);
         State::new(shape, alpha)
     }
 }
+
+// draws lines in color over a copy of current and returns the new score
+fn draw_and_score(target: &Pixels, current: &Pixels, buffer: &mut Pixels, color: &Color,
+                  lines: &[Scanline], score: f32) -> f32 {
+    buffer.copy_lines(current, lines);
+    buffer.draw_lines(color, lines);
+    Pixels::difference_partial(target, current, buffer, score, lines)
+}